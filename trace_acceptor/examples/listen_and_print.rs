@@ -1,20 +1,65 @@
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
 use std::thread;
+use std::time::Duration;
 use log::{error, info, LevelFilter};
 use simple_logger::SimpleLogger;
-use trace_acceptor::{TRACE_RESULT_FILE, TraceAcceptor};
+use trace_acceptor::{TRACE_RESULT_FILE, TimestampOrigin, TraceAcceptor};
+use trace_acceptor::sink::CsvSink;
+
+struct Args {
+    path: String,
+    csv_path: Option<String>,
+    checkpoint_path: Option<String>,
+    rate_window_ms: Option<u64>,
+    downsample_us: Option<u64>,
+    last_secs: Option<u64>,
+    dump_path: String,
+    tsc_time: bool,
+}
+
+fn parse_args() -> Args {
+    // [output path] [--csv <path>] [--checkpoint <path>] [--rates <window ms>] [--downsample <bucket us>]
+    // [--last <secs>] [--dump <path>] [--tsc-time]. trace.json by default, pass e.g. trace.json.gz to compress.
+    // With --last, typing "dump" on stdin saves the last <secs> of capture to the --dump path (last.json)
+    let mut res = Args {
+        path: "trace.json".to_string(),
+        csv_path: None,
+        checkpoint_path: None,
+        rate_window_ms: None,
+        downsample_us: None,
+        last_secs: None,
+        dump_path: "last.json".to_string(),
+        tsc_time: false,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--csv" => res.csv_path = args.next(),
+            "--checkpoint" => res.checkpoint_path = args.next(),
+            "--rates" => res.rate_window_ms = args.next().and_then(|ms| ms.parse().ok()),
+            "--downsample" => res.downsample_us = args.next().and_then(|us| us.parse().ok()),
+            "--last" => res.last_secs = args.next().and_then(|secs| secs.parse().ok()),
+            "--dump" => res.dump_path = args.next().unwrap_or(res.dump_path),
+            "--tsc-time" => res.tsc_time = true,
+            _ => res.path = arg,
+        }
+    }
+    res
+}
 
 fn save_res_and_exit() {
-    //save as trace.json
+    let Args { path, .. } = parse_args();
     let trace_data = TRACE_RESULT_FILE.lock().unwrap();
     let events_cnt = trace_data.trace_events.len();
     if events_cnt > 5_000_000 {
         error!("you dumbass really want to save {} events to your hard drive? fuck you!", events_cnt);
         std::process::exit(0);
     }
-    info!("Events count: {}. Saving to trace.json...", events_cnt);
-    let trace_data = serde_json::to_string(&*trace_data).unwrap();
-    std::fs::write("trace.json", trace_data).unwrap();
+    info!("Events count: {}. Saving to {}...", events_cnt, path);
+    trace_data.save(path).unwrap();
 
     std::process::exit(0);
 }
@@ -34,7 +79,38 @@ fn main() {
     }).unwrap();
 
     SimpleLogger::new().with_level(LevelFilter::Info).init().unwrap();
-    TraceAcceptor::new().listen();
+    let mut acceptor = TraceAcceptor::new();
+    let args = parse_args();
+    if let Some(window_ms) = args.rate_window_ms {
+        acceptor.set_rate_counters(Duration::from_millis(window_ms));
+    }
+    if let Some(bucket_us) = args.downsample_us {
+        acceptor.set_downsample(Duration::from_micros(bucket_us));
+    }
+    if args.tsc_time {
+        acceptor.set_timestamp_origin(TimestampOrigin::Tsc);
+    }
+    if let Some(secs) = args.last_secs {
+        acceptor.set_flight_recorder(Duration::from_secs(secs), args.dump_path);
+        thread::spawn(|| {
+            for line in io::stdin().lines() {
+                match line.as_deref().map(str::trim) {
+                    Ok("dump") => trace_acceptor::request_dump(),
+                    Ok(cmd) => error!("Unknown command {:?}, expected \"dump\"", cmd),
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+    if let Some(csv_path) = args.csv_path {
+        info!("Writing CSV to {}", csv_path);
+        acceptor.add_sink(Box::new(CsvSink::new(BufWriter::new(File::create(csv_path).unwrap()))));
+    }
+    if let Some(checkpoint_path) = args.checkpoint_path {
+        info!("Writing checkpoints to {}", checkpoint_path);
+        acceptor.set_checkpoint_file(checkpoint_path).unwrap();
+    }
+    acceptor.listen();
     if !IS_EXITING.compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed).is_err() {
         save_res_and_exit();
     }
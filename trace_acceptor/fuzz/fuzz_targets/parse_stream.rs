@@ -0,0 +1,21 @@
+//! Arbitrary bytes from an untrusted producer must never panic the stream parser.
+//!
+//! Run: `cargo +nightly fuzz run parse_stream` from trace_acceptor/
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use trace_acceptor::ParsingStateMachine;
+
+fuzz_target!(|data: &[u8]| {
+    // Split into two reads, so parser state carried between reads is covered too
+    let split = data.first().map_or(0, |&b| b as usize % (data.len() + 1));
+
+    let mut parser = ParsingStateMachine::default();
+    let mut events = parser.parse_many(&data[..split]);
+    events.extend(parser.parse_many(&data[split..]));
+    events.extend(parser.finish());
+
+    // Each event takes at least id byte and 2 timestamp bytes
+    assert!(events.len() <= data.len() / 3);
+});
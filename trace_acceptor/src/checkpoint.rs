@@ -0,0 +1,168 @@
+//! Crash-tolerant incremental dump of decoded events in Chrome JSON Array format.
+//! Closing `]` is optional in that format, so a file cut after any checkpoint still loads in Perfetto.
+//!
+//! Next to the file, `<path>.idx` gets one JSON line per checkpoint with the time range, tracks and byte range
+//! of its events, so tools can seek into a large capture instead of loading it whole.
+
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::perfetto_format::{PerfettoTraceEvent, PointEvent};
+use crate::{TimestampOrigin, TimestampReconstructor, TracingEvent};
+
+/// Index line of one checkpoint. Bytes `offset..offset + len` of the checkpoint file are its events,
+/// each preceded by `,` and/or a newline: strip the leading comma and wrap in `[]` to parse them.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct IndexEntry {
+    /// Timestamps of the first and last event, ns
+    pub start_ns: u64,
+    pub end_ns: u64,
+    pub tids: BTreeSet<u64>,
+    pub offset: u64,
+    pub len: u64,
+}
+
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct CheckpointWriter {
+    writer: CountingWriter<BufWriter<File>>,
+    index: BufWriter<File>,
+    empty: bool,
+    timeline: TimestampReconstructor,
+}
+
+pub fn index_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".idx");
+    name.into()
+}
+
+impl CheckpointWriter {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut writer = CountingWriter {
+            inner: BufWriter::new(File::create(path)?),
+            written: 0,
+        };
+        writer.write_all(b"[")?;
+        Ok(Self {
+            writer,
+            index: BufWriter::new(File::create(index_path(path))?),
+            empty: true,
+            timeline: TimestampReconstructor::default(),
+        })
+    }
+
+    /// Should be set before the first checkpoint
+    pub fn set_timestamp_origin(&mut self, origin: TimestampOrigin) {
+        self.timeline = TimestampReconstructor::new(origin);
+    }
+
+    pub fn write_event(&mut self, event: &PerfettoTraceEvent) -> io::Result<()> {
+        if !self.empty {
+            self.writer.write_all(b",")?;
+        }
+        self.empty = false;
+        self.writer.write_all(b"\n")?;
+        serde_json::to_writer(&mut self.writer, event)?;
+        Ok(())
+    }
+
+    /// Convert and append next decoded events of the stream, then flush everything to disk
+    pub fn checkpoint(&mut self, events: &[TracingEvent]) -> io::Result<()> {
+        let offset = self.writer.written;
+        let mut entry: Option<IndexEntry> = None;
+        for event in events {
+            let timestamp = self.timeline.next(event);
+            let tid = event.0 as u64;
+            match &mut entry {
+                Some(entry) => {
+                    entry.end_ns = timestamp;
+                    entry.tids.insert(tid);
+                }
+                None => entry = Some(IndexEntry {
+                    start_ns: timestamp,
+                    end_ns: timestamp,
+                    tids: BTreeSet::from([tid]),
+                    offset,
+                    len: 0,
+                }),
+            }
+            self.write_event(&PerfettoTraceEvent::Point(PointEvent::new(format!("{:?}", event.0), event.0 as u8, timestamp)))?;
+        }
+        // Index only points at data already on disk
+        self.writer.flush()?;
+
+        if let Some(mut entry) = entry {
+            entry.len = self.writer.written - offset;
+            serde_json::to_writer(&mut self.index, &entry)?;
+            self.index.write_all(b"\n")?;
+            self.index.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.write_all(b"\n]")?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::TracingEventId;
+
+    #[test]
+    fn index_points_at_checkpoint_events() {
+        let path = std::env::temp_dir().join(format!("tracer_checkpoint_{}.json", std::process::id()));
+        let mut writer = CheckpointWriter::create(&path).unwrap();
+        let first: Vec<_> = (0..10).map(|i| TracingEvent(TracingEventId::from(i % 2), 0, 1)).collect();
+        let second: Vec<_> = (0..5).map(|_| TracingEvent(TracingEventId::from(7), 0, 1)).collect();
+        writer.checkpoint(&first).unwrap();
+        writer.checkpoint(&[]).unwrap();
+        writer.checkpoint(&second).unwrap();
+        writer.finish().unwrap();
+
+        let data = fs::read(&path).unwrap();
+        let index = fs::read_to_string(index_path(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(index_path(&path)).unwrap();
+
+        let entries: Vec<IndexEntry> = index.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].tids, BTreeSet::from([0, 1]));
+        assert_eq!(entries[1].tids, BTreeSet::from([7]));
+        assert!(entries[0].end_ns < entries[1].start_ns);
+
+        for (entry, count) in entries.iter().zip([10, 5]) {
+            let slice = &data[entry.offset as usize..(entry.offset + entry.len) as usize];
+            let slice = std::str::from_utf8(slice).unwrap();
+            let events: Vec<serde_json::Value> = serde_json::from_str(&format!("[{}]", slice.trim_start().trim_start_matches(','))).unwrap();
+            assert_eq!(events.len(), count);
+            let ts = |e: &serde_json::Value| (e["ts"].as_f64().unwrap() * 1_000.0).round() as u64;
+            assert_eq!(ts(&events[0]), entry.start_ns);
+            assert_eq!(ts(events.last().unwrap()), entry.end_ns);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Collapses dense runs of same-name point events into one event carrying the count,
+//! so huge captures stay renderable.
+
+use std::time::Duration;
+use crate::perfetto_format::PerfettoTraceFile;
+use crate::{TracingEvent, TracingEventId};
+
+struct Run {
+    id: TracingEventId,
+    bucket: u64,
+    timestamp: u64,
+    count: u64,
+}
+
+pub struct Downsampler {
+    bucket_ns: u64,
+    run: Option<Run>,
+}
+
+impl Downsampler {
+    pub fn new(bucket: Duration) -> Self {
+        Self {
+            bucket_ns: (bucket.as_nanos() as u64).max(1),
+            run: None,
+        }
+    }
+
+    pub fn event(&mut self, event: &TracingEvent, timestamp: u64, file: &mut PerfettoTraceFile) {
+        let bucket = timestamp / self.bucket_ns;
+        if let Some(run) = &mut self.run {
+            if run.id == event.0 && run.bucket == bucket {
+                run.count += 1;
+                return;
+            }
+        }
+        self.finish(file);
+        self.run = Some(Run {
+            id: event.0,
+            bucket,
+            timestamp,
+            count: 1,
+        });
+    }
+
+    /// Emit the pending run
+    pub fn finish(&mut self, file: &mut PerfettoTraceFile) {
+        if let Some(run) = self.run.take() {
+            let name = format!("{:?}", run.id);
+            // Single events keep the exact form of the full-fidelity output
+            if run.count == 1 {
+                file.add_point_event(name, run.id as u8, run.timestamp);
+            }
+            else {
+                file.add_point_event_with_count(name, run.id as u8, run.timestamp, run.count);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perfetto_format::PerfettoTraceEvent;
+
+    /// (name, timestamp ns, count of collapsed events)
+    fn downsample(bucket_ns: u64, events: &[(TracingEventId, u64)]) -> Vec<(String, u64, Option<u64>)> {
+        let mut file = PerfettoTraceFile::new();
+        let mut downsampler = Downsampler::new(Duration::from_nanos(bucket_ns));
+        for &(id, timestamp) in events {
+            downsampler.event(&TracingEvent(id, 0, 0), timestamp, &mut file);
+        }
+        downsampler.finish(&mut file);
+        file.trace_events.iter()
+            .map(|event| match event {
+                PerfettoTraceEvent::Point(e) => (e.name.clone(), (e.ts * 1_000.0).round() as u64, e.args.get("count").copied()),
+                _ => panic!("only point events expected"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn runs_split_at_bucket_boundary() {
+        use TracingEventId::*;
+        let res = downsample(1_000, &[(MainLoopStart, 0), (MainLoopStart, 500), (MainLoopStart, 999), (MainLoopStart, 1_000), (MainLoopStart, 1_999)]);
+        assert_eq!(res, [
+            ("MainLoopStart".to_string(), 0, Some(3)),
+            ("MainLoopStart".to_string(), 1_000, Some(2)),
+        ]);
+    }
+
+    #[test]
+    fn other_name_splits_run_within_bucket() {
+        use TracingEventId::*;
+        let res = downsample(1_000, &[(MainLoopStart, 0), (MainLoopStart, 10), (MainLoopEnd, 20), (MainLoopStart, 30)]);
+        assert_eq!(res, [
+            ("MainLoopStart".to_string(), 0, Some(2)),
+            ("MainLoopEnd".to_string(), 20, None),
+            ("MainLoopStart".to_string(), 30, None),
+        ]);
+    }
+
+    #[test]
+    fn empty_buckets_emit_nothing() {
+        use TracingEventId::*;
+        let res = downsample(1_000, &[(DriversPoll, 100), (DriversPoll, 5_100), (DriversPoll, 5_200)]);
+        // Single events keep their exact form
+        assert_eq!(res, [
+            ("DriversPoll".to_string(), 100, None),
+            ("DriversPoll".to_string(), 5_100, Some(2)),
+        ]);
+        assert!(downsample(1_000, &[]).is_empty());
+    }
+}
@@ -0,0 +1,80 @@
+//! Keeps only the most recent window of capture time in memory, for always-on receivers.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use crate::{TimestampReconstructor, TracingEvent};
+
+pub struct FlightRecorder {
+    window_ns: u64,
+    timeline: TimestampReconstructor,
+    /// Timestamps of buffered events, in the same order
+    timestamps: VecDeque<u64>,
+}
+
+impl FlightRecorder {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window_ns: window.as_nanos() as u64,
+            timeline: TimestampReconstructor::default(),
+            timestamps: VecDeque::new(),
+        }
+    }
+
+    /// Track events appended to the buffer since the last call
+    pub fn record(&mut self, events: &[TracingEvent]) {
+        for event in events {
+            self.timestamps.push_back(self.timeline.next(event));
+        }
+    }
+
+    /// Remove leading `events` older than the window from the newest one, returns their number.
+    /// Evicted events still advance `timeline`, so kept ones keep their absolute time.
+    pub fn evict(&mut self, events: &mut Vec<TracingEvent>, timeline: &mut TimestampReconstructor) -> usize {
+        let Some(&newest) = self.timestamps.back() else {
+            return 0;
+        };
+        let cutoff = newest.saturating_sub(self.window_ns);
+        let evicted = self.timestamps.partition_point(|&ts| ts < cutoff);
+        self.timestamps.drain(..evicted);
+        for event in events.drain(..evicted) {
+            timeline.next(&event);
+        }
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TimestampOrigin, TracingEventId};
+
+    #[test]
+    fn evicts_events_outside_window() {
+        // 1 period is 65536 ticks, ~26.3us
+        let events: Vec<_> = (0..20).map(|i| TracingEvent(TracingEventId::from(i as u8 % 3), 0, 1)).collect();
+        let all_timestamps: Vec<_> = {
+            let mut timeline = TimestampReconstructor::new(TimestampOrigin::FirstEvent);
+            events.iter().map(|e| timeline.next(e)).collect()
+        };
+
+        let window = Duration::from_micros(100);
+        let mut recorder = FlightRecorder::new(window);
+        let mut buffered = events.clone();
+        let mut timeline = TimestampReconstructor::new(TimestampOrigin::FirstEvent);
+        recorder.record(&buffered);
+        let evicted = recorder.evict(&mut buffered, &mut timeline);
+
+        let newest = *all_timestamps.last().unwrap();
+        let window_ns = window.as_nanos() as u64;
+        assert!(newest - all_timestamps[evicted] <= window_ns);
+        assert!(newest - all_timestamps[evicted - 1] > window_ns);
+        assert_eq!(buffered.len(), events.len() - evicted);
+
+        // Kept events keep their absolute timestamps
+        let kept: Vec<_> = buffered.iter().map(|e| timeline.next(e)).collect();
+        assert_eq!(kept, all_timestamps[evicted..]);
+
+        // Nothing more to evict until new events arrive
+        assert_eq!(recorder.evict(&mut buffered, &mut timeline), 0);
+    }
+}
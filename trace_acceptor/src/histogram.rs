@@ -0,0 +1,118 @@
+//! Streaming histogram of time gaps between consecutive events, shows how bursty a stream is.
+
+use std::fmt;
+
+const BAR_WIDTH: u64 = 40;
+
+/// Power of two buckets: bucket 0 counts zero gaps, bucket `i` counts gaps in `[2^(i-1), 2^i)` ns
+#[derive(Clone)]
+pub struct GapHistogram {
+    buckets: [u64; 65],
+    last: Option<u64>,
+}
+
+impl Default for GapHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; 65],
+            last: None,
+        }
+    }
+}
+
+impl GapHistogram {
+    /// Timestamp of the next event, ns
+    pub fn record(&mut self, timestamp: u64) {
+        if let Some(last) = self.last {
+            let gap = timestamp.saturating_sub(last);
+            self.buckets[(u64::BITS - gap.leading_zeros()) as usize] += 1;
+        }
+        self.last = Some(timestamp);
+    }
+
+    pub fn buckets(&self) -> &[u64; 65] {
+        &self.buckets
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+}
+
+fn format_ns(ns: u128) -> String {
+    match ns {
+        0..1_000 => format!("{}ns", ns),
+        1_000..1_000_000 => format!("{}us", ns / 1_000),
+        1_000_000..1_000_000_000 => format!("{}ms", ns / 1_000_000),
+        _ => format!("{}s", ns / 1_000_000_000),
+    }
+}
+
+impl fmt::Display for GapHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(first) = self.buckets.iter().position(|&c| c > 0) else {
+            return write!(f, "no gaps recorded");
+        };
+        let last = self.buckets.iter().rposition(|&c| c > 0).unwrap();
+        let max = *self.buckets.iter().max().unwrap();
+        for (i, &count) in self.buckets.iter().enumerate().take(last + 1).skip(first) {
+            let range = match i {
+                0 => "0".to_string(),
+                _ => format!("{}..{}", format_ns(1 << (i - 1)), format_ns(1 << i)),
+            };
+            let bar = (count * BAR_WIDTH).div_ceil(max) as usize;
+            writeln!(f, "{:>12} |{:<width$} {}", range, "#".repeat(bar), count, width = BAR_WIDTH as usize)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(gaps: &[u64]) -> GapHistogram {
+        let mut histogram = GapHistogram::default();
+        let mut timestamp = 0;
+        histogram.record(timestamp);
+        for gap in gaps {
+            timestamp += gap;
+            histogram.record(timestamp);
+        }
+        histogram
+    }
+
+    #[test]
+    fn first_buckets() {
+        let histogram = histogram(&[0, 0, 1, 2, 3, 4]);
+        assert_eq!(histogram.buckets()[..4], [2, 1, 2, 1]);
+        assert_eq!(histogram.count(), 6);
+    }
+
+    #[test]
+    fn last_bucket() {
+        let mut histogram = GapHistogram::default();
+        histogram.record(0);
+        histogram.record(1 << 63);
+        histogram.record(u64::MAX);
+        assert_eq!(histogram.buckets()[64], 1);
+        assert_eq!(histogram.buckets()[63], 1);
+        assert_eq!(histogram.count(), 2);
+    }
+
+    #[test]
+    fn backwards_timestamp_counts_as_zero_gap() {
+        let mut histogram = GapHistogram::default();
+        histogram.record(1_000);
+        histogram.record(10);
+        assert_eq!(histogram.buckets()[0], 1);
+        assert_eq!(histogram.count(), 1);
+    }
+
+    #[test]
+    fn first_event_has_no_gap() {
+        let histogram = histogram(&[]);
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.to_string(), "no gaps recorded");
+    }
+}
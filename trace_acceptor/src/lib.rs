@@ -1,25 +1,58 @@
 mod perfetto_format;
+mod proto_format;
+mod checkpoint;
+mod rate_counter;
+mod downsample;
+mod flight_recorder;
+pub mod sink;
+pub mod receiver;
+pub mod histogram;
+
+pub use perfetto_format::{PerfettoTraceEvent, PerfettoTraceFile};
 
 use std::{mem, thread};
 use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use interprocess::local_socket::traits::ListenerExt;
 use lazy_static::lazy_static;
-use log::{debug, error, info};
-use crate::perfetto_format::PerfettoTraceFile;
+use log::{debug, error, info, warn};
+use crate::checkpoint::CheckpointWriter;
+use crate::downsample::Downsampler;
+use crate::flight_recorder::FlightRecorder;
+use crate::histogram::GapHistogram;
+use crate::rate_counter::RateCounters;
+use crate::sink::TraceSink;
 
 pub struct TraceAcceptor {
     stream_parser: ParsingStateMachine,
+    checkpoint: Option<CheckpointWriter>,
+    sinks: Vec<Box<dyn TraceSink + Send>>,
+    rate_counters: Option<RateCounters>,
+    gaps: GapHistogram,
+    downsampler: Option<Downsampler>,
+    flight_recorder: Option<FlightRecorder>,
+    dump_path: Option<PathBuf>,
+    timestamp_origin: TimestampOrigin,
 }
 
 const MEASURE_DUR_NS: usize = 19;
 
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask a running [TraceAcceptor::listen] to save its flight recorder window, see [TraceAcceptor::set_flight_recorder].
+/// Safe to call from a signal handler thread or an admin command.
+pub fn request_dump() {
+    DUMP_REQUESTED.store(true, Ordering::Relaxed);
+}
+
 lazy_static! {
     pub static ref TRACE_RESULT_FILE: Mutex<PerfettoTraceFile> = Mutex::new(PerfettoTraceFile::new());
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum TracingEventId {
     MainLoopStart,
@@ -77,47 +110,110 @@ use TracingEventId::*;
 impl TraceAcceptor {
     pub fn new() -> Self {
         Self {
-            stream_parser: ParsingStateMachine::EventId
+            stream_parser: ParsingStateMachine::EventId,
+            checkpoint: None,
+            sinks: Vec::new(),
+            rate_counters: None,
+            gaps: GapHistogram::default(),
+            downsampler: None,
+            flight_recorder: None,
+            dump_path: None,
+            timestamp_origin: TimestampOrigin::FirstEvent,
+        }
+    }
+
+    /// Origin of timestamps in all outputs: checkpoint, sinks and [TRACE_RESULT_FILE]
+    pub fn set_timestamp_origin(&mut self, origin: TimestampOrigin) {
+        self.timestamp_origin = origin;
+    }
+
+    /// Keep only events of the last `window` of capture time, older ones are evicted once per second.
+    /// Bounds memory of long captures, the result holds the last `window` before disconnect.
+    ///
+    /// While capturing, [request_dump] saves the current window to `dump_path` (any format [PerfettoTraceFile::save]
+    /// supports, overwritten on each dump). Request is picked up after the next read returns, so a silent producer delays it.
+    pub fn set_flight_recorder(&mut self, window: Duration, dump_path: impl Into<PathBuf>) {
+        self.flight_recorder = Some(FlightRecorder::new(window));
+        self.dump_path = Some(dump_path.into());
+    }
+
+    fn dump_window(&self, events: &[TracingEvent], timeline: &TimestampReconstructor, capture_start: SystemTime) {
+        let Some(path) = &self.dump_path else {
+            warn!("Dump requested, but flight recorder is not enabled");
+            return;
+        };
+        let mut file = PerfettoTraceFile::new();
+        write_metadata(&mut file, capture_start);
+        let mut timeline = timeline.clone();
+        for event in events {
+            let timestamp = timeline.next(event);
+            file.event(event, timestamp).unwrap();
+        }
+        match file.save(path) {
+            Ok(()) => info!("Dumped last {} events to {}", events.len(), path.display()),
+            Err(e) => error!("Failed to dump events to {}: {}", path.display(), e),
+        }
+    }
+
+    /// Collapse consecutive same-name events within each `bucket` of time into one event with a count,
+    /// in [TRACE_RESULT_FILE] only. Checkpoint, sinks and rate counters still get every event.
+    pub fn set_downsample(&mut self, bucket: Duration) {
+        self.downsampler = Some(Downsampler::new(bucket));
+    }
+
+    /// Gaps between consecutive events of the last capture, filled once [TraceAcceptor::listen] returns
+    pub fn inter_arrival_histogram(&self) -> &GapHistogram {
+        &self.gaps
+    }
+
+    /// Add a counter track per event name with the number of events in each `window` of capture time
+    pub fn set_rate_counters(&mut self, window: Duration) {
+        self.rate_counters = Some(RateCounters::new(window));
+    }
+
+    /// Also feed decoded events into `sink`, next to [TRACE_RESULT_FILE]. Failing sink is reported and dropped.
+    pub fn add_sink(&mut self, sink: Box<dyn TraceSink + Send>) {
+        self.sinks.push(sink);
+    }
+
+    /// Additionally append decoded events to `path` once per second, so a killed receiver loses
+    /// at most the last second of capture. File is Chrome JSON Array format, loadable even if cut.
+    pub fn set_checkpoint_file(&mut self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        self.checkpoint = Some(CheckpointWriter::create(path)?);
+        Ok(())
+    }
+
+    fn write_checkpoint(&mut self, events: &[TracingEvent]) {
+        if let Some(checkpoint) = &mut self.checkpoint {
+            if let Err(e) = checkpoint.checkpoint(events) {
+                error!("Failed to write checkpoint, disabling it: {}", e);
+                self.checkpoint = None;
+            }
         }
     }
 
     pub fn listen(&mut self)  {
         // let udp_socket = UdpSocket::bind("0.0.0.0:4302").unwrap();
 
-        let mut total_pr = 0;
-        let mut first = true;
+        let mut timeline = TimestampReconstructor::new(self.timestamp_origin);
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.set_timestamp_origin(self.timestamp_origin);
+        }
+        self.gaps = GapHistogram::default();
+        DUMP_REQUESTED.store(false, Ordering::Relaxed);
+        let capture_start = SystemTime::now();
         info!("Listening for incoming packets...");
 
         {
             let mut trace_res_file = TRACE_RESULT_FILE.lock().unwrap();
-            trace_res_file.set_thread_name(MainLoopStart as u8, format!("{:?}", MainLoopStart));
-            trace_res_file.set_thread_name(MainLoopEnd as u8, format!("{:?}", MainLoopEnd));
-            trace_res_file.set_thread_name(DriversPoll as u8, format!("{:?}", DriversPoll));
-            trace_res_file.set_thread_name(PlcLogicIterStart as u8, format!("{:?}", PlcLogicIterStart));
-            trace_res_file.set_thread_name(PlcLogicIterEnd as u8, format!("{:?}", PlcLogicIterEnd));
-            trace_res_file.set_thread_name(I2cExpanderDriverPoll as u8, format!("{:?}", I2cExpanderDriverPoll));
-            trace_res_file.set_thread_name(DebugConnectionDriverNewCmd as u8, format!("{:?}", DebugConnectionDriverNewCmd));
-            trace_res_file.set_thread_name(Rs485DriverPoll as u8, format!("{:?}", Rs485DriverPoll));
-            trace_res_file.set_thread_name(IomGetStart as u8, format!("{:?}", IomGetStart));
-            trace_res_file.set_thread_name(PlcPollStart as u8, format!("{:?}", PlcPollStart));
-            trace_res_file.set_thread_name(IomSetStart as u8, format!("{:?}", IomSetStart));
-            trace_res_file.set_thread_name(RetainOperationStart as u8, format!("{:?}", RetainOperationStart));
-            trace_res_file.set_thread_name(I2CWriteOperationStart as u8, format!("{:?}", I2CWriteOperationStart));
-            trace_res_file.set_thread_name(I2CWriteOperationEnd as u8, format!("{:?}", I2CWriteOperationEnd));
-            trace_res_file.set_thread_name(I2CWriteOperationEndErr as u8, format!("{:?}", I2CWriteOperationEndErr));
-            trace_res_file.set_thread_name(I2CReadOperationStart as u8, format!("{:?}", I2CReadOperationStart));
-            trace_res_file.set_thread_name(I2CReadOperationEnd as u8, format!("{:?}", I2CReadOperationEnd));
-            trace_res_file.set_thread_name(I2CReadOperationEndErr as u8, format!("{:?}", I2CReadOperationEndErr));
-            trace_res_file.set_thread_name(I2CWakerCall as u8, format!("{:?}", I2CWakerCall));
-            trace_res_file.set_thread_name(I2CWakerCallErr as u8, format!("{:?}", I2CWakerCallErr));
-            trace_res_file.set_thread_name(SpiOpStart as u8, format!("{:?}", SpiOpStart));
-            trace_res_file.set_thread_name(SpiOpFail as u8, format!("{:?}", SpiOpFail));
-            trace_res_file.set_thread_name(SpiOpEnd as u8, format!("{:?}", SpiOpEnd));
-            trace_res_file.set_thread_name(DmaOpStart as u8, format!("{:?}", DmaOpStart));
-            trace_res_file.set_thread_name(DmaOpEnd as u8, format!("{:?}", DmaOpEnd));
-            trace_res_file.set_thread_name(DmaOpEndErr as u8, format!("{:?}", DmaOpEndErr));
-            trace_res_file.set_thread_name(DmaWakerCall as u8, format!("{:?}", DmaWakerCall));
-            trace_res_file.set_thread_name(DmaPollFn as u8, format!("{:?}", DmaPollFn));
+            write_metadata(&mut trace_res_file, capture_start);
+
+            if let Some(checkpoint) = &mut self.checkpoint {
+                if let Err(e) = trace_res_file.trace_events.iter().try_for_each(|event| checkpoint.write_event(event)) {
+                    error!("Failed to write checkpoint, disabling it: {}", e);
+                    self.checkpoint = None;
+                }
+            }
         }
 
 
@@ -152,16 +248,25 @@ impl TraceAcceptor {
         let mut packets_cnt = 0;
 
         let mut events = Vec::with_capacity(10_000_000);
+        let mut checkpointed = 0;
         loop {
             let c = con.read(&mut buf).unwrap();
             if c == 0 {
                 info!("Client disconnected! Exiting...");
+                events.extend(self.stream_parser.finish());
                 break;
             }
             let new_events = self.stream_parser.parse_many(&buf[..c]);
             let new_events_len = new_events.len();
+            if let Some(flight_recorder) = &mut self.flight_recorder {
+                flight_recorder.record(&new_events);
+            }
             events.extend(new_events);
 
+            if DUMP_REQUESTED.swap(false, Ordering::Relaxed) {
+                self.dump_window(&events, &timeline, capture_start);
+            }
+
             debug!("Got {} bytes, Parsed {} events", c, new_events_len);
             bytes_cnt += c;
             events_cnt += new_events_len;
@@ -180,33 +285,178 @@ impl TraceAcceptor {
                 bytes_cnt = 0;
                 events_cnt = 0;
                 packets_cnt = 0;
+
+                self.write_checkpoint(&events[checkpointed..]);
+                checkpointed = events.len();
+
+                if let Some(flight_recorder) = &mut self.flight_recorder {
+                    checkpointed -= flight_recorder.evict(&mut events, &mut timeline);
+                }
+            }
+        }
+
+        self.write_checkpoint(&events[checkpointed..]);
+        if let Some(checkpoint) = self.checkpoint.take() {
+            if let Err(e) = checkpoint.finish() {
+                error!("Failed to finish checkpoint: {}", e);
             }
         }
 
         info!("Disconnected... Start parsing");
 
-        for event in events {
-            if first {
-                first = false;
+        {
+            let mut trace_res_file = TRACE_RESULT_FILE.lock().unwrap();
+            for event in events {
+                let timestamp = timeline.next(&event);
+                self.gaps.record(timestamp);
+                match &mut self.downsampler {
+                    Some(downsampler) => downsampler.event(&event, timestamp, &mut trace_res_file),
+                    None => trace_res_file.event(&event, timestamp).unwrap(),
+                }
+                if let Some(rate_counters) = &mut self.rate_counters {
+                    rate_counters.event(&format!("{:?}", event.0), timestamp, &mut trace_res_file);
+                }
+                self.sinks.retain_mut(|sink| match sink.event(&event, timestamp) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        error!("Trace sink failed, dropping it: {}", e);
+                        false
+                    }
+                });
             }
-            else {
-                total_pr += event.2;
+            if let Some(downsampler) = &mut self.downsampler {
+                downsampler.finish(&mut trace_res_file);
+            }
+            if let Some(rate_counters) = &mut self.rate_counters {
+                rate_counters.finish(&mut trace_res_file);
+            }
+        }
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.finish() {
+                error!("Failed to finish trace sink: {}", e);
             }
-            // add to trace file
-            let mut trace_res_file = TRACE_RESULT_FILE.lock().unwrap();
-            trace_res_file.add_point_event(format!("{:?}", event.0), event.0 as u8, ((event.1 as u64 | (total_pr << 16)) as f64 / 2.495) as u64);
         }
 
-        info!("Total PR: {}", total_pr);
+        info!("Total PR: {}", timeline.total_pr());
+        info!("Inter-arrival times:\n{}", self.gaps);
+
+        let trace_res_file = TRACE_RESULT_FILE.lock().unwrap();
+        let crossing = trace_res_file.crossing_slices();
+        if !crossing.is_empty() {
+            warn!("{} slices overlap without nesting, trace data may be corrupted", crossing.len());
+            for (a, b) in crossing.iter().take(10) {
+                warn!("Slice #{} crosses slice #{}", b, a);
+            }
+        }
 
         info!("Finished!");
 
     }
 }
 
+/// Capture start time and names of event id tracks
+fn write_metadata(trace_res_file: &mut PerfettoTraceFile, capture_start: SystemTime) {
+    let start_time = capture_start.duration_since(UNIX_EPOCH).unwrap_or_default();
+    trace_res_file.set_trace_metadata("capture_start_unix_ms".to_string(), start_time.as_millis().to_string());
+    trace_res_file.set_thread_name(MainLoopStart as u8, format!("{:?}", MainLoopStart));
+    trace_res_file.set_thread_name(MainLoopEnd as u8, format!("{:?}", MainLoopEnd));
+    trace_res_file.set_thread_name(DriversPoll as u8, format!("{:?}", DriversPoll));
+    trace_res_file.set_thread_name(PlcLogicIterStart as u8, format!("{:?}", PlcLogicIterStart));
+    trace_res_file.set_thread_name(PlcLogicIterEnd as u8, format!("{:?}", PlcLogicIterEnd));
+    trace_res_file.set_thread_name(I2cExpanderDriverPoll as u8, format!("{:?}", I2cExpanderDriverPoll));
+    trace_res_file.set_thread_name(DebugConnectionDriverNewCmd as u8, format!("{:?}", DebugConnectionDriverNewCmd));
+    trace_res_file.set_thread_name(Rs485DriverPoll as u8, format!("{:?}", Rs485DriverPoll));
+    trace_res_file.set_thread_name(IomGetStart as u8, format!("{:?}", IomGetStart));
+    trace_res_file.set_thread_name(PlcPollStart as u8, format!("{:?}", PlcPollStart));
+    trace_res_file.set_thread_name(IomSetStart as u8, format!("{:?}", IomSetStart));
+    trace_res_file.set_thread_name(RetainOperationStart as u8, format!("{:?}", RetainOperationStart));
+    trace_res_file.set_thread_name(I2CWriteOperationStart as u8, format!("{:?}", I2CWriteOperationStart));
+    trace_res_file.set_thread_name(I2CWriteOperationEnd as u8, format!("{:?}", I2CWriteOperationEnd));
+    trace_res_file.set_thread_name(I2CWriteOperationEndErr as u8, format!("{:?}", I2CWriteOperationEndErr));
+    trace_res_file.set_thread_name(I2CReadOperationStart as u8, format!("{:?}", I2CReadOperationStart));
+    trace_res_file.set_thread_name(I2CReadOperationEnd as u8, format!("{:?}", I2CReadOperationEnd));
+    trace_res_file.set_thread_name(I2CReadOperationEndErr as u8, format!("{:?}", I2CReadOperationEndErr));
+    trace_res_file.set_thread_name(I2CWakerCall as u8, format!("{:?}", I2CWakerCall));
+    trace_res_file.set_thread_name(I2CWakerCallErr as u8, format!("{:?}", I2CWakerCallErr));
+    trace_res_file.set_thread_name(SpiOpStart as u8, format!("{:?}", SpiOpStart));
+    trace_res_file.set_thread_name(SpiOpFail as u8, format!("{:?}", SpiOpFail));
+    trace_res_file.set_thread_name(SpiOpEnd as u8, format!("{:?}", SpiOpEnd));
+    trace_res_file.set_thread_name(DmaOpStart as u8, format!("{:?}", DmaOpStart));
+    trace_res_file.set_thread_name(DmaOpEnd as u8, format!("{:?}", DmaOpEnd));
+    trace_res_file.set_thread_name(DmaOpEndErr as u8, format!("{:?}", DmaOpEndErr));
+    trace_res_file.set_thread_name(DmaWakerCall as u8, format!("{:?}", DmaWakerCall));
+    trace_res_file.set_thread_name(DmaPollFn as u8, format!("{:?}", DmaPollFn));
+}
+
+/// CPU cycles per ns of the capturing machine
+const CPU_FREQ_GHZ: f64 = 2.495;
+
+/// Where output timestamps count from
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum TimestampOrigin {
+    /// Timeline starts at 0 with the first event of the capture
+    #[default]
+    FirstEvent,
+    /// Raw TSC converted to ns, for correlation with other TSC-based data. Producer's first event carries
+    /// the absolute TSC period, so this is only valid if the capture starts with it
+    Tsc,
+}
+
+/// Rebuilds event timestamps from the stream: each event carries the lower 16 bits of TSC
+/// and the number of 2^16 cycle periods passed since the previous event
+#[derive(Default, Clone)]
+pub struct TimestampReconstructor {
+    origin: TimestampOrigin,
+    started: bool,
+    total_pr: u64,
+    origin_ticks: u64,
+}
+
+impl TimestampReconstructor {
+    pub fn new(origin: TimestampOrigin) -> Self {
+        Self {
+            origin,
+            ..Self::default()
+        }
+    }
+
+    /// Timestamp of the next event in the stream, ns
+    pub fn next(&mut self, event: &TracingEvent) -> u64 {
+        let ticks = self.next_ticks(event);
+        self.ticks_to_origin_ns(ticks)
+    }
+
+    /// Convert ticks returned by [TimestampReconstructor::next_ticks] to ns since the origin
+    pub fn ticks_to_origin_ns(&self, ticks: u64) -> u64 {
+        Self::ticks_to_ns(ticks.saturating_sub(self.origin_ticks))
+    }
+
+    /// Timestamp of the next event in raw TSC ticks, whatever the origin. As with [TimestampOrigin::Tsc],
+    /// ticks are absolute only if the capture starts with the producer's first event
+    pub fn next_ticks(&mut self, event: &TracingEvent) -> u64 {
+        self.total_pr += event.2;
+        let ticks = event.1 as u64 | (self.total_pr << 16);
+        if !self.started {
+            self.started = true;
+            if self.origin == TimestampOrigin::FirstEvent {
+                self.origin_ticks = ticks;
+            }
+        }
+        ticks
+    }
+
+    fn ticks_to_ns(ticks: u64) -> u64 {
+        (ticks as f64 / CPU_FREQ_GHZ) as u64
+    }
+
+    pub fn total_pr(&self) -> u64 {
+        self.total_pr
+    }
+}
+
 /// event, timestamp end (cpu cycles), dif_pr (24 bits)
 #[derive(Debug, Copy, Clone)]
-pub struct TracingEvent(TracingEventId, u16, u64);
+pub struct TracingEvent(pub TracingEventId, pub u16, pub u64);
 
 #[derive(Copy,Clone, Default)]
 pub enum ParsingStateMachine {
@@ -239,7 +489,9 @@ impl ParsingStateMachine {
                     Some(TracingEvent(event_id, now, pr))
                 }
                 else {
-                    *self = ParsingStateMachine::TimestampPrOrEventId(event_id, now, ((b as u64) << cur_shift as u64) | pr, cur_shift + 7);
+                    // Producer never sends more than 64 bits, extra bytes of malformed stream are ignored
+                    let pr = if cur_shift < 64 { ((b as u64) << cur_shift as u64) | pr } else { pr };
+                    *self = ParsingStateMachine::TimestampPrOrEventId(event_id, now, pr, cur_shift.saturating_add(7));
                     None
                 }
             }
@@ -249,4 +501,12 @@ impl ParsingStateMachine {
     pub fn parse_many(&mut self, bytes: &[u8]) -> Vec<TracingEvent> {
         bytes.iter().flat_map(|b| self.next_byte(*b)).collect()
     }
+
+    /// Event is only emitted when next event starts, so the last one is taken out at the end of stream
+    pub fn finish(&mut self) -> Option<TracingEvent> {
+        match mem::take(self) {
+            ParsingStateMachine::TimestampPrOrEventId(event_id, now, pr, _) => Some(TracingEvent(event_id, now, pr)),
+            _ => None,
+        }
+    }
 }
\ No newline at end of file
@@ -1,11 +1,23 @@
 use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct PerfettoTraceFile {
     pub trace_events: Vec<PerfettoTraceEvent>,
     pub thread_names: HashMap<u64, String>,
+    /// Trace-level metadata (capture host, command line, start time...)
+    #[serde(default)]
+    pub other_data: HashMap<String, String>,
+    /// Event name -> `cname`, replaces color derived from the name
+    #[serde(skip)]
+    pub color_overrides: HashMap<String, String>,
 }
 
 impl PerfettoTraceFile {
@@ -13,28 +25,184 @@ impl PerfettoTraceFile {
         Self {
             trace_events: vec![],
             thread_names: HashMap::new(),
+            other_data: HashMap::new(),
+            color_overrides: HashMap::new(),
+        }
+    }
+
+    /// Save as JSON, gzipped if the path ends with `.gz` (Perfetto loads `.json.gz` directly).
+    /// Paths ending with `.pftrace` or `.perfetto-trace` are saved in native protobuf format.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if path.extension().is_some_and(|ext| ext == "pftrace" || ext == "perfetto-trace") {
+            return self.write_proto_to(BufWriter::new(File::create(path)?));
+        }
+        let gzip = path.extension().is_some_and(|ext| ext == "gz");
+        self.save_with(path, gzip)
+    }
+
+    pub fn save_with(&self, path: impl AsRef<Path>, gzip: bool) -> io::Result<()> {
+        self.write_to(BufWriter::new(File::create(path)?), gzip)
+    }
+
+    /// Serialize as JSON into any writer, e.g. stdout or a response body. Writer should be buffered.
+    pub fn write_to<W: Write>(&self, mut writer: W, gzip: bool) -> io::Result<()> {
+        if gzip {
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            serde_json::to_writer(&mut encoder, self)?;
+            encoder.finish()?.flush()
+        }
+        else {
+            serde_json::to_writer(&mut writer, self)?;
+            writer.flush()
+        }
+    }
+
+    /// Serialize as Perfetto protobuf `Trace`, smaller and faster to load than JSON. Writer should be buffered.
+    pub fn write_proto_to<W: Write>(&self, writer: W) -> io::Result<()> {
+        crate::proto_format::write_proto(self, writer)
+    }
+
+    /// Find range events on the same track which overlap without one containing the other,
+    /// a sign of corrupted data or clock issues. Returns index pairs into `trace_events`.
+    pub fn crossing_slices(&self) -> Vec<(usize, usize)> {
+        // Slices closer than this (us) are treated as touching, float rounding of ts + dur
+        const EPS: f64 = 1e-3;
+
+        let mut slices: Vec<(usize, &RangeEvent)> = self.trace_events.iter().enumerate()
+            .filter_map(|(i, event)| match event {
+                PerfettoTraceEvent::Range(e) => Some((i, e)),
+                _ => None,
+            })
+            .collect();
+        // Per track by start, enclosing slice first
+        slices.sort_by(|(_, a), (_, b)| a.tid.cmp(&b.tid)
+            .then(a.ts.total_cmp(&b.ts))
+            .then(b.dur.total_cmp(&a.dur)));
+
+        let mut res = Vec::new();
+        let mut stack: Vec<(usize, &RangeEvent)> = Vec::new();
+        for (i, slice) in slices {
+            while let Some((_, top)) = stack.last() {
+                if top.tid != slice.tid || top.ts + top.dur <= slice.ts + EPS {
+                    stack.pop();
+                }
+                else {
+                    break;
+                }
+            }
+            if let Some((parent_i, parent)) = stack.last() {
+                if slice.ts + slice.dur > parent.ts + parent.dur + EPS {
+                    res.push((*parent_i, i));
+                    continue;
+                }
+            }
+            stack.push((i, slice));
         }
+        res
     }
 
     pub fn add_range_event(&mut self, name: String, event_id: u8, timestamp: u64, duration: u32) {
-        self.trace_events.push(PerfettoTraceEvent::Range(RangeEvent::new(name, event_id, timestamp, duration)));
+        let mut event = RangeEvent::new(name, event_id, timestamp, duration);
+        if let Some(color) = self.color_overrides.get(&event.name) {
+            event.cname = color.clone();
+        }
+        self.trace_events.push(PerfettoTraceEvent::Range(event));
     }
 
     pub fn add_point_event(&mut self, name: String, event_id: u8, timestamp: u64) {
-        self.trace_events.push(PerfettoTraceEvent::Point(PointEvent::new(name, event_id, timestamp)));
+        let mut event = PointEvent::new(name, event_id, timestamp);
+        if let Some(color) = self.color_overrides.get(&event.name) {
+            event.cname = color.clone();
+        }
+        self.trace_events.push(PerfettoTraceEvent::Point(event));
     }
 
+    /// Start of an async slice. Slices with different `id` may overlap, which nested range events can't express
+    pub fn add_async_begin(&mut self, name: String, id: u64, event_id: u8, timestamp: u64) {
+        self.trace_events.push(PerfettoTraceEvent::Async(AsyncEvent::new(name, "b", id, event_id, timestamp)));
+    }
+
+    /// End of the async slice started with the same `name` and `id`
+    pub fn add_async_end(&mut self, name: String, id: u64, event_id: u8, timestamp: u64) {
+        self.trace_events.push(PerfettoTraceEvent::Async(AsyncEvent::new(name, "e", id, event_id, timestamp)));
+    }
+
+    /// Sample of a counter track named `name`, shown as a graph
+    pub fn add_counter_event(&mut self, name: String, timestamp: u64, value: u64) {
+        self.trace_events.push(PerfettoTraceEvent::Counter(CounterEvent::new(name, timestamp, value)));
+    }
+
+    /// Point event standing for `count` collapsed events, count is shown in its args
+    pub fn add_point_event_with_count(&mut self, name: String, event_id: u8, timestamp: u64, count: u64) {
+        self.add_point_event(name, event_id, timestamp);
+        if let Some(PerfettoTraceEvent::Point(event)) = self.trace_events.last_mut() {
+            event.args.insert("count".to_string(), count);
+        }
+    }
+
+    /// Pin events named `name` to a color from Chrome's reserved `cname` list (e.g. "good", "bad", "yellow").
+    /// Applies to events added after the call.
+    pub fn set_color(&mut self, name: String, cname: String) {
+        self.color_overrides.insert(name, cname);
+    }
+
+    /// Processes with lower index are shown on top when several processes are merged
+    pub fn set_process_sort_index(&mut self, pid: u64, sort_index: i64) {
+        self.trace_events.push(PerfettoTraceEvent::ProcessSortIndex(ProcessSortIndexMeta::new(pid, sort_index)));
+    }
+
+    pub fn set_trace_metadata(&mut self, key: String, value: String) {
+        self.other_data.insert(key, value);
+    }
+
+    /// Metadata event is emitted only if the thread has no name yet or the name changed
     pub fn set_thread_name(&mut self, event_id: u8, thread_name: String) {
+        if self.thread_names.get(&(event_id as u64)) == Some(&thread_name) {
+            return;
+        }
+        self.thread_names.insert(event_id as u64, thread_name.clone());
         self.trace_events.push(PerfettoTraceEvent::ThreadName(ThreadNameMeta::new(event_id, thread_name)));
     }
 }
 
+/// Reserved color names understood by trace viewers
+const PALETTE: [&str; 12] = [
+    "thread_state_running",
+    "thread_state_runnable",
+    "thread_state_iowait",
+    "rail_response",
+    "rail_animation",
+    "rail_idle",
+    "rail_load",
+    "startup",
+    "good",
+    "bad",
+    "yellow",
+    "olive",
+];
+
+/// Color of an event name, same in every run and every file: FNV-1a doesn't depend on
+/// process, platform or compiler version
+pub fn stable_color(name: &str) -> &'static str {
+    let mut hash: u32 = 0x811C_9DC5;
+    for b in name.bytes() {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    PALETTE[hash as usize % PALETTE.len()]
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum PerfettoTraceEvent {
     Range(RangeEvent),
+    // Before Point: async event has all of its fields
+    Async(AsyncEvent),
     Point(PointEvent),
-    ThreadName(ThreadNameMeta)
+    ThreadName(ThreadNameMeta),
+    ProcessSortIndex(ProcessSortIndexMeta),
+    Counter(CounterEvent),
 }
 
 
@@ -46,17 +214,20 @@ pub struct RangeEvent {
     pub ts: f64,
     pub dur: f64,
     pub tid: u64,
+    #[serde(default)]
+    pub cname: String,
 }
 
 impl RangeEvent {
     pub fn new(name: String, event_id: u8, timestamp: u64, duration: u32) -> Self {
         Self {
-            name,
             cat: "Range".to_string(),
             ph: "X".to_string(),
             ts: (timestamp as f64) / 1_000.0,
             dur: (duration as f64) / 1_000.0,
             tid: event_id as u64,
+            cname: stable_color(&name).to_string(),
+            name,
         }
     }
 }
@@ -68,16 +239,47 @@ pub struct PointEvent {
     pub ph: String,
     pub ts: f64,
     pub tid: u64,
+    #[serde(default)]
+    pub cname: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub args: HashMap<String, u64>,
 }
 
 impl PointEvent {
     pub fn new(name: String, event_id: u8, timestamp: u64) -> Self {
         Self {
-            name,
             cat: "Point".to_string(),
             ph: "i".to_string(),
             ts: (timestamp as f64) / 1_000.0,
             tid: event_id as u64,
+            cname: stable_color(&name).to_string(),
+            name,
+            args: HashMap::new(),
+        }
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct AsyncEvent {
+    pub name: String,
+    pub cat: String,
+    pub ph: String,
+    /// Correlates begin with end
+    pub id: u64,
+    pub ts: f64,
+    pub tid: u64,
+}
+
+impl AsyncEvent {
+    pub fn new(name: String, ph: &str, id: u64, event_id: u8, timestamp: u64) -> Self {
+        Self {
+            name,
+            cat: "Async".to_string(),
+            ph: ph.to_string(),
+            id,
+            ts: (timestamp as f64) / 1_000.0,
+            tid: event_id as u64,
         }
     }
 }
@@ -100,4 +302,44 @@ impl ThreadNameMeta {
             args: HashMap::from([("name".to_string(), thread_name)]),
         }
     }
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct ProcessSortIndexMeta {
+    pub name: String,
+    pub ph: String,
+    pub pid: u64,
+    pub args: HashMap<String, i64>,
+}
+
+impl ProcessSortIndexMeta {
+    pub fn new(pid: u64, sort_index: i64) -> Self {
+        Self {
+            name: "process_sort_index".to_string(),
+            ph: "M".to_string(),
+            pid,
+            args: HashMap::from([("sort_index".to_string(), sort_index)]),
+        }
+    }
+}
+
+
+#[derive(Serialize, Deserialize)]
+pub struct CounterEvent {
+    pub name: String,
+    pub ph: String,
+    pub ts: f64,
+    pub args: HashMap<String, u64>,
+}
+
+impl CounterEvent {
+    pub fn new(name: String, timestamp: u64, value: u64) -> Self {
+        Self {
+            name,
+            ph: "C".to_string(),
+            ts: (timestamp as f64) / 1_000.0,
+            args: HashMap::from([("value".to_string(), value)]),
+        }
+    }
 }
\ No newline at end of file
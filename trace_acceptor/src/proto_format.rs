@@ -0,0 +1,322 @@
+//! Minimal hand-written encoder of Perfetto's native `Trace` protobuf, covering only the fields
+//! the receiver produces: clock snapshot, track and thread descriptors, instant/slice/counter track events.
+//!
+//! Field numbers from perfetto/protos/perfetto/trace/{trace,trace_packet,clock_snapshot}.proto,
+//! track_event/*.proto and common/builtin_clock.proto
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::Write;
+use crate::perfetto_format::{PerfettoTraceEvent, PerfettoTraceFile};
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_LEN: u32 = 2;
+
+// Trace
+const TRACE_PACKET: u32 = 1;
+// TracePacket
+const PACKET_CLOCK_SNAPSHOT: u32 = 6;
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_TRACK_EVENT: u32 = 11;
+const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+// ClockSnapshot
+const SNAPSHOT_CLOCKS: u32 = 1;
+const SNAPSHOT_PRIMARY_TRACE_CLOCK: u32 = 2;
+// ClockSnapshot.Clock
+const CLOCK_ID: u32 = 1;
+const CLOCK_TIMESTAMP: u32 = 2;
+// BuiltinClock
+const CLOCK_BOOTTIME: u64 = 6;
+// TrackDescriptor
+const TRACK_UUID: u32 = 1;
+const TRACK_NAME: u32 = 2;
+const TRACK_THREAD: u32 = 4;
+const TRACK_COUNTER: u32 = 8;
+// ThreadDescriptor
+const THREAD_PID: u32 = 1;
+const THREAD_TID: u32 = 2;
+const THREAD_NAME: u32 = 5;
+// TrackEvent
+const EVENT_TYPE: u32 = 9;
+const EVENT_TRACK_UUID: u32 = 11;
+const EVENT_NAME: u32 = 23;
+const EVENT_COUNTER_VALUE: u32 = 30;
+// TrackEvent.Type
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+const TYPE_COUNTER: u64 = 4;
+
+const SEQUENCE_ID: u64 = 1;
+/// Producer pid is not in the stream, all event id threads go into one process
+const PID: u64 = 1;
+/// Track uuids of async slices and counters, above the u8 event id tracks
+const ASYNC_TRACK_BASE: u64 = 1 << 32;
+const COUNTER_TRACK_BASE: u64 = 1 << 33;
+
+fn varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn key(out: &mut Vec<u8>, field: u32, wire_type: u32) {
+    varint(out, ((field << 3) | wire_type) as u64);
+}
+
+fn uint_field(out: &mut Vec<u8>, field: u32, v: u64) {
+    key(out, field, WIRE_VARINT);
+    varint(out, v);
+}
+
+fn bytes_field(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    key(out, field, WIRE_LEN);
+    varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Uuid 0 is Perfetto's default track, event id 0 must not land there.
+/// Also used as the thread's tid, as tid 0 is the idle thread in Perfetto
+fn thread_track(tid: u64) -> u64 {
+    tid + 1
+}
+
+fn us_to_ns(ts: f64) -> u64 {
+    (ts * 1_000.0).round() as u64
+}
+
+struct Encoder<W: Write> {
+    writer: W,
+    described_tracks: HashSet<u64>,
+    counter_tracks: HashMap<String, u64>,
+}
+
+impl<W: Write> Encoder<W> {
+    fn packet(&mut self, timestamp: Option<u64>, field: u32, body: &[u8]) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(body.len() + 16);
+        if let Some(timestamp) = timestamp {
+            uint_field(&mut packet, PACKET_TIMESTAMP, timestamp);
+        }
+        uint_field(&mut packet, PACKET_SEQUENCE_ID, SEQUENCE_ID);
+        bytes_field(&mut packet, field, body);
+
+        let mut framed = Vec::with_capacity(packet.len() + 8);
+        bytes_field(&mut framed, TRACE_PACKET, &packet);
+        self.writer.write_all(&framed)
+    }
+
+    /// Declare the clock domain of packet timestamps. They count from the [crate::TimestampOrigin],
+    /// which is not tied to any system clock, so only the trace clock itself is listed
+    fn clock_snapshot(&mut self) -> io::Result<()> {
+        let mut clock = Vec::new();
+        uint_field(&mut clock, CLOCK_ID, CLOCK_BOOTTIME);
+        uint_field(&mut clock, CLOCK_TIMESTAMP, 0);
+
+        let mut snapshot = Vec::new();
+        bytes_field(&mut snapshot, SNAPSHOT_CLOCKS, &clock);
+        uint_field(&mut snapshot, SNAPSHOT_PRIMARY_TRACE_CLOCK, CLOCK_BOOTTIME);
+        self.packet(None, PACKET_CLOCK_SNAPSHOT, &snapshot)
+    }
+
+    fn track(&mut self, uuid: u64, name: &str, counter: bool) -> io::Result<()> {
+        if !self.described_tracks.insert(uuid) {
+            return Ok(());
+        }
+        let mut track = Vec::new();
+        uint_field(&mut track, TRACK_UUID, uuid);
+        bytes_field(&mut track, TRACK_NAME, name.as_bytes());
+        if counter {
+            bytes_field(&mut track, TRACK_COUNTER, &[]);
+        }
+        self.packet(None, PACKET_TRACK_DESCRIPTOR, &track)
+    }
+
+    /// Track of an event id, shown as a thread of one process
+    fn thread_track(&mut self, tid: u64, name: &str) -> io::Result<()> {
+        let uuid = thread_track(tid);
+        if !self.described_tracks.insert(uuid) {
+            return Ok(());
+        }
+        let mut thread = Vec::new();
+        uint_field(&mut thread, THREAD_PID, PID);
+        uint_field(&mut thread, THREAD_TID, uuid);
+        bytes_field(&mut thread, THREAD_NAME, name.as_bytes());
+
+        let mut track = Vec::new();
+        uint_field(&mut track, TRACK_UUID, uuid);
+        bytes_field(&mut track, TRACK_NAME, name.as_bytes());
+        bytes_field(&mut track, TRACK_THREAD, &thread);
+        self.packet(None, PACKET_TRACK_DESCRIPTOR, &track)
+    }
+
+    fn track_event(&mut self, timestamp: u64, track_uuid: u64, event_type: u64, name: Option<&str>, counter_value: Option<u64>) -> io::Result<()> {
+        let mut event = Vec::new();
+        uint_field(&mut event, EVENT_TYPE, event_type);
+        uint_field(&mut event, EVENT_TRACK_UUID, track_uuid);
+        if let Some(name) = name {
+            bytes_field(&mut event, EVENT_NAME, name.as_bytes());
+        }
+        if let Some(value) = counter_value {
+            uint_field(&mut event, EVENT_COUNTER_VALUE, value);
+        }
+        self.packet(Some(timestamp), PACKET_TRACK_EVENT, &event)
+    }
+}
+
+pub fn write_proto<W: Write>(file: &PerfettoTraceFile, writer: W) -> io::Result<()> {
+    let mut encoder = Encoder {
+        writer,
+        described_tracks: HashSet::new(),
+        counter_tracks: HashMap::new(),
+    };
+    encoder.clock_snapshot()?;
+    for event in &file.trace_events {
+        match event {
+            PerfettoTraceEvent::ThreadName(e) => {
+                let name = e.args.get("name").map_or(e.name.as_str(), String::as_str);
+                encoder.thread_track(e.tid, name)?;
+            }
+            PerfettoTraceEvent::Point(e) => {
+                encoder.thread_track(e.tid, &e.tid.to_string())?;
+                encoder.track_event(us_to_ns(e.ts), thread_track(e.tid), TYPE_INSTANT, Some(&e.name), None)?;
+            }
+            PerfettoTraceEvent::Range(e) => {
+                encoder.thread_track(e.tid, &e.tid.to_string())?;
+                encoder.track_event(us_to_ns(e.ts), thread_track(e.tid), TYPE_SLICE_BEGIN, Some(&e.name), None)?;
+                encoder.track_event(us_to_ns(e.ts + e.dur), thread_track(e.tid), TYPE_SLICE_END, None, None)?;
+            }
+            PerfettoTraceEvent::Async(e) => {
+                // One track per correlation id, so overlapping slices don't have to nest
+                let uuid = ASYNC_TRACK_BASE | (e.id & (ASYNC_TRACK_BASE - 1));
+                encoder.track(uuid, &e.name, false)?;
+                let event_type = if e.ph == "b" { TYPE_SLICE_BEGIN } else { TYPE_SLICE_END };
+                let name = (event_type == TYPE_SLICE_BEGIN).then_some(e.name.as_str());
+                encoder.track_event(us_to_ns(e.ts), uuid, event_type, name, None)?;
+            }
+            PerfettoTraceEvent::Counter(e) => {
+                let next_uuid = COUNTER_TRACK_BASE + encoder.counter_tracks.len() as u64;
+                let uuid = *encoder.counter_tracks.entry(e.name.clone()).or_insert(next_uuid);
+                encoder.track(uuid, &e.name, true)?;
+                encoder.track_event(us_to_ns(e.ts), uuid, TYPE_COUNTER, None, e.args.get("value").copied())?;
+            }
+            PerfettoTraceEvent::ProcessSortIndex(_) => {}
+        }
+    }
+    encoder.writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Varint(u64),
+        Bytes(Vec<u8>),
+    }
+
+    fn read_varint(bytes: &[u8], pos: &mut usize) -> u64 {
+        let mut v = 0;
+        let mut shift = 0;
+        loop {
+            let b = bytes[*pos];
+            *pos += 1;
+            v |= ((b & 0x7F) as u64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                return v;
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Vec<(u32, Value)> {
+        let mut pos = 0;
+        let mut fields = Vec::new();
+        while pos < bytes.len() {
+            let key = read_varint(bytes, &mut pos);
+            let value = match (key & 7) as u32 {
+                WIRE_VARINT => Value::Varint(read_varint(bytes, &mut pos)),
+                WIRE_LEN => {
+                    let len = read_varint(bytes, &mut pos) as usize;
+                    pos += len;
+                    Value::Bytes(bytes[pos - len..pos].to_vec())
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            fields.push(((key >> 3) as u32, value));
+        }
+        fields
+    }
+
+    fn message(value: &Value) -> Vec<(u32, Value)> {
+        match value {
+            Value::Bytes(bytes) => decode(bytes),
+            Value::Varint(_) => panic!("expected nested message"),
+        }
+    }
+
+    fn field(fields: &[(u32, Value)], number: u32) -> &Value {
+        &fields.iter().find(|(n, _)| *n == number).unwrap_or_else(|| panic!("no field {number}")).1
+    }
+
+    #[test]
+    fn encodes_tracks_and_events() {
+        let mut file = PerfettoTraceFile::new();
+        file.set_thread_name(0, "MainLoopStart".to_string());
+        file.add_point_event("MainLoopStart".to_string(), 0, 1_500);
+        file.add_range_event("range".to_string(), 0, 2_000, 1_000);
+        file.add_counter_event("rate".to_string(), 3_000, 7);
+
+        let mut bytes = Vec::new();
+        write_proto(&file, &mut bytes).unwrap();
+
+        let packets: Vec<_> = decode(&bytes).iter()
+            .map(|(number, value)| {
+                assert_eq!(*number, TRACE_PACKET);
+                message(value)
+            })
+            .collect();
+        assert_eq!(packets.len(), 7);
+        assert!(packets.iter().all(|p| *field(p, PACKET_SEQUENCE_ID) == Value::Varint(SEQUENCE_ID)));
+
+        let snapshot = message(field(&packets[0], PACKET_CLOCK_SNAPSHOT));
+        assert_eq!(*field(&snapshot, SNAPSHOT_PRIMARY_TRACE_CLOCK), Value::Varint(CLOCK_BOOTTIME));
+        let clock = message(field(&snapshot, SNAPSHOT_CLOCKS));
+        assert_eq!(*field(&clock, CLOCK_ID), Value::Varint(CLOCK_BOOTTIME));
+        assert_eq!(*field(&clock, CLOCK_TIMESTAMP), Value::Varint(0));
+        let packets = &packets[1..];
+
+        // Thread track of event id 0 is not the default track, nor the idle thread
+        let track = message(field(&packets[0], PACKET_TRACK_DESCRIPTOR));
+        assert_eq!(*field(&track, TRACK_UUID), Value::Varint(1));
+        assert_eq!(*field(&track, TRACK_NAME), Value::Bytes(b"MainLoopStart".to_vec()));
+        let thread = message(field(&track, TRACK_THREAD));
+        assert_eq!(*field(&thread, THREAD_PID), Value::Varint(PID));
+        assert_eq!(*field(&thread, THREAD_TID), Value::Varint(1));
+        assert_eq!(*field(&thread, THREAD_NAME), Value::Bytes(b"MainLoopStart".to_vec()));
+
+        assert_eq!(*field(&packets[1], PACKET_TIMESTAMP), Value::Varint(1_500));
+        let instant = message(field(&packets[1], PACKET_TRACK_EVENT));
+        assert_eq!(*field(&instant, EVENT_TYPE), Value::Varint(TYPE_INSTANT));
+        assert_eq!(*field(&instant, EVENT_TRACK_UUID), Value::Varint(1));
+        assert_eq!(*field(&instant, EVENT_NAME), Value::Bytes(b"MainLoopStart".to_vec()));
+
+        let begin = message(field(&packets[2], PACKET_TRACK_EVENT));
+        assert_eq!(*field(&begin, EVENT_TYPE), Value::Varint(TYPE_SLICE_BEGIN));
+        assert_eq!(*field(&packets[3], PACKET_TIMESTAMP), Value::Varint(3_000));
+        let end = message(field(&packets[3], PACKET_TRACK_EVENT));
+        assert_eq!(*field(&end, EVENT_TYPE), Value::Varint(TYPE_SLICE_END));
+
+        let counter_track = message(field(&packets[4], PACKET_TRACK_DESCRIPTOR));
+        assert_eq!(*field(&counter_track, TRACK_UUID), Value::Varint(COUNTER_TRACK_BASE));
+        assert!(counter_track.iter().all(|(number, _)| *number != TRACK_THREAD));
+        assert_eq!(*field(&counter_track, TRACK_COUNTER), Value::Bytes(vec![]));
+        let counter = message(field(&packets[5], PACKET_TRACK_EVENT));
+        assert_eq!(*field(&counter, EVENT_TYPE), Value::Varint(TYPE_COUNTER));
+        assert_eq!(*field(&counter, EVENT_TRACK_UUID), Value::Varint(COUNTER_TRACK_BASE));
+        assert_eq!(*field(&counter, EVENT_COUNTER_VALUE), Value::Varint(7));
+    }
+}
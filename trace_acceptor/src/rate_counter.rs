@@ -0,0 +1,104 @@
+//! Derived "events per window" counter tracks, one per event name.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use crate::perfetto_format::PerfettoTraceFile;
+
+pub struct RateCounters {
+    window_ns: u64,
+    /// Index of the window being counted
+    window: Option<u64>,
+    /// Every name seen so far, so names absent from a window are reported as 0
+    counts: BTreeMap<String, u64>,
+}
+
+impl RateCounters {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window_ns: (window.as_nanos() as u64).max(1),
+            window: None,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    pub fn event(&mut self, name: &str, timestamp: u64, file: &mut PerfettoTraceFile) {
+        let window = timestamp / self.window_ns;
+        if let Some(cur) = self.window {
+            if window != cur {
+                self.emit(cur, file);
+                // Counter holds its value until the next sample, so drop to 0 over empty windows
+                if window > cur + 1 {
+                    self.emit(cur + 1, file);
+                }
+            }
+        }
+        self.window = Some(window);
+        match self.counts.get_mut(name) {
+            Some(count) => *count += 1,
+            None => {
+                self.counts.insert(name.to_string(), 1);
+            }
+        }
+    }
+
+    /// Emit the last window
+    pub fn finish(&mut self, file: &mut PerfettoTraceFile) {
+        if let Some(cur) = self.window.take() {
+            self.emit(cur, file);
+        }
+    }
+
+    fn emit(&mut self, window: u64, file: &mut PerfettoTraceFile) {
+        for (name, count) in &mut self.counts {
+            file.add_counter_event(format!("{} rate", name), window * self.window_ns, *count);
+            *count = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::perfetto_format::PerfettoTraceEvent;
+
+    /// (counter name, window start ns, count)
+    fn rates(window_ns: u64, events: &[(&str, u64)]) -> Vec<(String, u64, u64)> {
+        let mut file = PerfettoTraceFile::new();
+        let mut counters = RateCounters::new(Duration::from_nanos(window_ns));
+        for &(name, timestamp) in events {
+            counters.event(name, timestamp, &mut file);
+        }
+        counters.finish(&mut file);
+        file.trace_events.iter()
+            .map(|event| match event {
+                PerfettoTraceEvent::Counter(e) => (e.name.clone(), (e.ts * 1_000.0).round() as u64, e.args["value"]),
+                _ => panic!("only counter events expected"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn counts_per_window() {
+        let res = rates(1_000, &[("a", 0), ("a", 999), ("b", 500), ("a", 1_000), ("b", 1_500), ("b", 1_999)]);
+        assert_eq!(res, [
+            ("a rate".to_string(), 0, 2),
+            ("b rate".to_string(), 0, 1),
+            ("a rate".to_string(), 1_000, 1),
+            ("b rate".to_string(), 1_000, 2),
+        ]);
+    }
+
+    #[test]
+    fn drops_to_zero_over_empty_windows() {
+        let res = rates(1_000, &[("a", 100), ("b", 200), ("a", 4_500)]);
+        assert_eq!(res, [
+            ("a rate".to_string(), 0, 1),
+            ("b rate".to_string(), 0, 1),
+            // One zero sample right after the last counted window holds until the next one
+            ("a rate".to_string(), 1_000, 0),
+            ("b rate".to_string(), 1_000, 0),
+            ("a rate".to_string(), 4_000, 1),
+            ("b rate".to_string(), 4_000, 0),
+        ]);
+    }
+}
@@ -0,0 +1,102 @@
+//! Decode pipeline as a library: iterate over decoded events instead of running the bundled listener.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use interprocess::local_socket::{prelude::*, GenericNamespaced, ListenerOptions, Stream};
+use crate::{ParsingStateMachine, TimestampOrigin, TimestampReconstructor, TracingEventId};
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// Perfetto track, same as in [crate::TraceAcceptor] output
+    pub tid: u64,
+    pub id: TracingEventId,
+    pub name: String,
+    /// Perfetto phase, events of the stream are instant ("i")
+    pub phase: &'static str,
+    /// ns since the [TimestampOrigin]
+    pub timestamp: u64,
+    /// Raw TSC ticks, whatever the [TimestampOrigin], see [TimestampReconstructor::next_ticks]. For correlation with hardware counters
+    pub raw_ts: u64,
+}
+
+/// Blocking iterator over events of one connection, ends when the producer disconnects
+pub struct EventReceiver<R: Read = Stream> {
+    reader: R,
+    buf: Vec<u8>,
+    parser: ParsingStateMachine,
+    timeline: TimestampReconstructor,
+    pending: VecDeque<Event>,
+    finished: bool,
+}
+
+impl EventReceiver<Stream> {
+    /// Listen on local socket `name` (e.g. "tracer.sock") and wait for the first producer to connect
+    pub fn bind(name: &str) -> io::Result<Self> {
+        let listener = ListenerOptions::new().name(name.to_ns_name::<GenericNamespaced>()?).create_sync()?;
+        let con = listener.incoming().next().unwrap()?;
+        Ok(Self::new(con))
+    }
+}
+
+impl<R: Read> EventReceiver<R> {
+    /// Decode events from any byte stream, e.g. a file with a recorded capture
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: vec![0; 64 * 1024],
+            parser: ParsingStateMachine::default(),
+            timeline: TimestampReconstructor::default(),
+            pending: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    /// Count timestamps from `origin` instead of the first event
+    pub fn with_timestamp_origin(mut self, origin: TimestampOrigin) -> Self {
+        self.timeline = TimestampReconstructor::new(origin);
+        self
+    }
+
+    fn push(&mut self, event: crate::TracingEvent) {
+        let raw_ts = self.timeline.next_ticks(&event);
+        self.pending.push_back(Event {
+            tid: event.0 as u64,
+            id: event.0,
+            name: format!("{:?}", event.0),
+            phase: "i",
+            timestamp: self.timeline.ticks_to_origin_ns(raw_ts),
+            raw_ts,
+        });
+    }
+}
+
+impl<R: Read> Iterator for EventReceiver<R> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() && !self.finished {
+            match self.reader.read(&mut self.buf) {
+                Ok(0) => {
+                    self.finished = true;
+                    if let Some(event) = self.parser.finish() {
+                        self.push(event);
+                    }
+                }
+                Ok(n) => {
+                    for i in 0..n {
+                        if let Some(event) = self.parser.next_byte(self.buf[i]) {
+                            self.push(event);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
@@ -0,0 +1,76 @@
+//! Output stage: every decoded event is fanned out to all registered sinks, so several formats
+//! are produced from a single decode pass.
+
+use std::io;
+use std::io::Write;
+use crate::perfetto_format::PerfettoTraceFile;
+use crate::TracingEvent;
+
+pub trait TraceSink {
+    /// Called for every decoded event in stream order, `timestamp` in ns
+    fn event(&mut self, event: &TracingEvent, timestamp: u64) -> io::Result<()>;
+
+    /// Called once after the last event
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TraceSink for PerfettoTraceFile {
+    fn event(&mut self, event: &TracingEvent, timestamp: u64) -> io::Result<()> {
+        self.add_point_event(format!("{:?}", event.0), event.0 as u8, timestamp);
+        Ok(())
+    }
+}
+
+/// Export events as CSV rows for spreadsheet/pandas analysis, streamed without keeping events in memory.
+/// Columns: tid,name,phase,timestamp,duration (timestamps in us, like JSON output)
+pub struct CsvSink<W: Write> {
+    writer: W,
+    header_written: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    /// Writer should be buffered
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            header_written: false,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            writeln!(self.writer, "tid,name,phase,timestamp,duration")?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> TraceSink for CsvSink<W> {
+    fn event(&mut self, event: &TracingEvent, timestamp: u64) -> io::Result<()> {
+        self.write_header()?;
+        let name = format!("{:?}", event.0);
+        writeln!(self.writer, "{},{},i,{},", event.0 as u8, csv_field(&name), timestamp as f64 / 1_000.0)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Empty capture still gets the header
+        self.write_header()?;
+        self.writer.flush()
+    }
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    }
+    else {
+        s.to_string()
+    }
+}
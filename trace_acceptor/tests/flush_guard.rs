@@ -0,0 +1,29 @@
+//! Events still buffered when the guard goes out of scope reach its writer
+
+use std::thread;
+use tracer::flush_guard::FlushGuard;
+use trace_acceptor::ParsingStateMachine;
+
+#[test]
+fn drop_flushes_buffered_events() {
+    // Fresh thread, so its default tracer holds only these events
+    let transport = thread::spawn(|| {
+        let mut transport = Vec::new();
+        {
+            let _guard = FlushGuard::new(&mut transport);
+            for i in 0..100 {
+                tracer::event(0x1111 + i % 2, "guarded");
+            }
+        }
+        transport
+    }).join().unwrap();
+
+    let mut parser = ParsingStateMachine::default();
+    let mut events = parser.parse_many(&transport);
+    events.extend(parser.finish());
+
+    assert_eq!(events.len(), 100);
+    for (i, event) in events.iter().enumerate() {
+        assert_eq!(event.0 as usize, i % 2 + 1, "corrupted event #{i}");
+    }
+}
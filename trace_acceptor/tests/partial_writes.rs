@@ -0,0 +1,76 @@
+//! Flushing into a writer that accepts only a few bytes at a time must not break the stream
+
+use std::io;
+use std::io::Write;
+use tracer::CleanupStrategy;
+use trace_acceptor::ParsingStateMachine;
+
+const EVENTS: usize = 10_000;
+
+/// Accepts at most 3 bytes per write, every other call would block
+struct TrickleWriter {
+    data: Vec<u8>,
+    calls: usize,
+}
+
+impl Write for TrickleWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.calls += 1;
+        if self.calls.is_multiple_of(2) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let n = buf.len().min(3);
+        self.data.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn short_and_would_block_writes_resume() {
+    let mut writer = TrickleWriter { data: Vec::new(), calls: 0 };
+
+    for i in 0..EVENTS {
+        tracer::event(0x1111 + (i % 2) as u32, "trickle");
+        if i % 100 == 0 {
+            tracer::try_flush(&mut writer).unwrap();
+        }
+    }
+    while !tracer::try_flush(&mut writer).unwrap() {}
+
+    let mut parser = ParsingStateMachine::default();
+    let mut events = parser.parse_many(&writer.data);
+    events.extend(parser.finish());
+
+    assert_eq!(events.len(), EVENTS);
+    for (i, event) in events.iter().enumerate() {
+        assert_eq!(event.0 as usize, i % 2 + 1, "corrupted event #{i}");
+    }
+}
+
+#[test]
+fn drop_oldest_keeps_partially_written_event() {
+    let mut writer = TrickleWriter { data: Vec::new(), calls: 0 };
+    tracer::configure_thread_buffer(256);
+    tracer::set_cleanup_strategy(CleanupStrategy::DropOldest);
+
+    for i in 0..EVENTS {
+        tracer::event(0x1111 + (i % 2) as u32, "trickle");
+        // Writer drains much slower than events come, so the buffer keeps overflowing with a cut event at its front
+        if i % 100 == 0 {
+            tracer::try_flush(&mut writer).unwrap();
+        }
+    }
+    while !tracer::try_flush(&mut writer).unwrap() {}
+
+    let mut parser = ParsingStateMachine::default();
+    let mut events = parser.parse_many(&writer.data);
+    events.extend(parser.finish());
+
+    assert!(tracer::dropped_events() > 0, "buffer limit was never hit");
+    assert_eq!(events.len() + tracer::dropped_events(), EVENTS);
+    assert!(events.iter().all(|e| e.0 as usize == 1 || e.0 as usize == 2), "corrupted event");
+}
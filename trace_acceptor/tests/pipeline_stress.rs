@@ -0,0 +1,132 @@
+//! End-to-end: producer threads -> thread local buffers -> flush into memory -> stream parser
+
+use std::arch::x86_64::_rdtsc;
+use std::thread;
+use tracer::CleanupStrategy;
+use trace_acceptor::{ParsingStateMachine, TimestampOrigin, TimestampReconstructor};
+
+const THREADS: usize = 4;
+const EVENTS_PER_THREAD: usize = 1_000_000;
+const FLUSH_EVERY: usize = 10_000;
+const BUFFER_CAPACITY: usize = 16 * 1024;
+const NAMES: [(u32, &str); 3] = [(0x1111, "first"), (0x2222, "second"), (0x3333, "third")];
+
+#[test]
+fn stress_pipeline_no_corruption() {
+    let producers: Vec<_> = (0..THREADS).map(|_| thread::spawn(|| {
+        let mut transport = Vec::new();
+        let start = unsafe { _rdtsc() };
+        for i in 0..EVENTS_PER_THREAD {
+            let (hash, name) = NAMES[i % NAMES.len()];
+            tracer::event(hash, name);
+            if (i + 1) % FLUSH_EVERY == 0 {
+                tracer::flush(&mut transport);
+            }
+        }
+        let end = unsafe { _rdtsc() };
+        tracer::flush(&mut transport);
+        (transport, start, end)
+    })).collect();
+
+    for producer in producers {
+        let (transport, start, end) = producer.join().unwrap();
+
+        // Odd chunk size, so events get split between reads
+        let mut parser = ParsingStateMachine::default();
+        let mut events = Vec::with_capacity(EVENTS_PER_THREAD);
+        for chunk in transport.chunks(4093) {
+            events.extend(parser.parse_many(chunk));
+        }
+        events.extend(parser.finish());
+
+        assert_eq!(events.len(), EVENTS_PER_THREAD);
+        for (i, event) in events.iter().enumerate() {
+            // Each thread interns names in order of first use, starting from 1
+            assert_eq!(event.0 as usize, i % NAMES.len() + 1, "corrupted event #{i}");
+        }
+
+        // Period deltas of one thread must not skip periods captured by the others
+        let mut timeline = TimestampReconstructor::new(TimestampOrigin::Tsc);
+        let ticks: Vec<u64> = events.iter().map(|event| timeline.next_ticks(event)).collect();
+        let (first, last) = (ticks[0], *ticks.last().unwrap());
+        assert!(ticks.is_sorted(), "timeline goes backwards");
+        assert!(start <= first && last <= end, "events at {first}..{last} outside of measured {start}..{end}");
+        let (span, measured) = (last - first, end - start);
+        assert!(span as f64 >= measured as f64 * 0.95, "reconstructed span {span} of measured {measured} ticks");
+    }
+}
+
+fn bounded_buffer_accounting(strategy: CleanupStrategy) {
+    let producers: Vec<_> = (0..THREADS).map(move |_| thread::spawn(move || {
+        tracer::configure_thread_buffer(BUFFER_CAPACITY);
+        tracer::set_cleanup_strategy(strategy);
+
+        let mut transport = Vec::new();
+        for i in 0..EVENTS_PER_THREAD {
+            let (hash, name) = NAMES[i % NAMES.len()];
+            tracer::event(hash, name);
+            if (i + 1) % FLUSH_EVERY == 0 {
+                tracer::flush(&mut transport);
+            }
+        }
+        tracer::flush(&mut transport);
+        (transport, tracer::dropped_events())
+    })).collect();
+
+    for producer in producers {
+        let (transport, dropped) = producer.join().unwrap();
+
+        let mut parser = ParsingStateMachine::default();
+        let mut events = parser.parse_many(&transport);
+        events.extend(parser.finish());
+
+        assert!(dropped > 0, "buffer limit was never hit");
+        assert_eq!(events.len() + dropped, EVENTS_PER_THREAD);
+        assert!(events.iter().all(|e| (1..=NAMES.len()).contains(&(e.0 as usize))), "corrupted event");
+    }
+}
+
+#[test]
+fn stress_pipeline_drop_newest_accounting() {
+    bounded_buffer_accounting(CleanupStrategy::DropNewest);
+}
+
+#[test]
+fn stress_pipeline_drop_oldest_accounting() {
+    bounded_buffer_accounting(CleanupStrategy::DropOldest);
+}
+
+#[test]
+fn stress_pipeline_rate_limit_accounting() {
+    const RATE: u32 = 10_000;
+
+    let producers: Vec<_> = (0..THREADS).map(|_| thread::spawn(|| {
+        tracer::set_rate_limit(Some(RATE));
+
+        let start = std::time::Instant::now();
+        let mut transport = Vec::new();
+        for i in 0..EVENTS_PER_THREAD {
+            let (hash, name) = NAMES[i % NAMES.len()];
+            tracer::event(hash, name);
+            if (i + 1) % FLUSH_EVERY == 0 {
+                tracer::flush(&mut transport);
+            }
+        }
+        tracer::flush(&mut transport);
+        (transport, tracer::rate_limited_events(), start.elapsed())
+    })).collect();
+
+    for producer in producers {
+        let (transport, limited, elapsed) = producer.join().unwrap();
+
+        let mut parser = ParsingStateMachine::default();
+        let mut events = parser.parse_many(&transport);
+        events.extend(parser.finish());
+
+        assert_eq!(events.len() + limited, EVENTS_PER_THREAD);
+        // Initial burst plus refill over the run
+        let allowed = RATE as f64 * (1.0 + elapsed.as_secs_f64());
+        assert!(events.len() as f64 <= allowed, "{} events passed, at most {} allowed", events.len(), allowed);
+        assert!(events.iter().all(|e| (1..=NAMES.len()).contains(&(e.0 as usize))), "corrupted event");
+    }
+}
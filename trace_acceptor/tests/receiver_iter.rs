@@ -0,0 +1,27 @@
+use std::arch::x86_64::_rdtsc;
+use std::io::Cursor;
+use trace_acceptor::receiver::EventReceiver;
+use trace_acceptor::TracingEventId;
+use tracer::ThreadLocalStorage;
+
+#[test]
+fn iterates_until_end_of_stream() {
+    let mut storage = ThreadLocalStorage::new();
+    let start = unsafe { _rdtsc() };
+    for i in 0..1000 {
+        storage.event(i % 3, ["a", "b", "c"][i as usize % 3]);
+    }
+    let end = unsafe { _rdtsc() };
+    let mut transport = Vec::new();
+    storage.flush_to(&mut transport).unwrap();
+
+    let events: Vec<_> = EventReceiver::new(Cursor::new(transport)).collect::<Result<_, _>>().unwrap();
+    assert_eq!(events.len(), 1000);
+    assert_eq!(events[0].id, TracingEventId::MainLoopEnd);
+    assert_eq!(events[0].name, "MainLoopEnd");
+    assert_eq!(events[0].timestamp, 0);
+    assert!(events.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+    assert!(events.windows(2).all(|w| w[0].raw_ts <= w[1].raw_ts));
+    // Raw TSC even with the default origin
+    assert!(start <= events[0].raw_ts && events[999].raw_ts <= end);
+}
@@ -0,0 +1,47 @@
+//! One decode pass fanned out to several output formats
+
+use trace_acceptor::sink::{CsvSink, TraceSink};
+use trace_acceptor::{ParsingStateMachine, PerfettoTraceEvent, PerfettoTraceFile, TimestampReconstructor};
+use tracer::ThreadLocalStorage;
+
+#[test]
+fn one_decode_pass_feeds_every_sink() {
+    let mut storage = ThreadLocalStorage::new();
+    for i in 0..100 {
+        storage.event(i % 4, ["a", "b", "c", "d"][i as usize % 4]);
+    }
+    let mut transport = Vec::new();
+    storage.flush_to(&mut transport).unwrap();
+
+    let mut parser = ParsingStateMachine::default();
+    let mut events = parser.parse_many(&transport);
+    events.extend(parser.finish());
+
+    let mut csv = CsvSink::new(Vec::new());
+    let mut file = PerfettoTraceFile::new();
+    {
+        let mut sinks: [&mut dyn TraceSink; 2] = [&mut csv, &mut file];
+        let mut timeline = TimestampReconstructor::default();
+        for event in &events {
+            let timestamp = timeline.next(event);
+            for sink in &mut sinks {
+                sink.event(event, timestamp).unwrap();
+            }
+        }
+        for sink in &mut sinks {
+            sink.finish().unwrap();
+        }
+    }
+
+    let csv = String::from_utf8(csv.into_inner()).unwrap();
+    let mut rows = csv.lines();
+    assert_eq!(rows.next(), Some("tid,name,phase,timestamp,duration"));
+    assert_eq!(file.trace_events.len(), events.len());
+    for (row, event) in rows.by_ref().zip(&file.trace_events) {
+        let PerfettoTraceEvent::Point(event) = event else {
+            panic!("unexpected event kind");
+        };
+        assert_eq!(row, format!("{},{},i,{},", event.tid, event.name, event.ts));
+    }
+    assert_eq!(csv.lines().count(), events.len() + 1);
+}
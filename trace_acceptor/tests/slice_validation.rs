@@ -0,0 +1,16 @@
+use trace_acceptor::TRACE_RESULT_FILE;
+
+#[test]
+fn crossing_slices_are_reported() {
+    let mut file = TRACE_RESULT_FILE.lock().unwrap();
+    // Nested and back-to-back slices on track 1
+    file.add_range_event("outer".to_string(), 1, 1_000, 10_000);
+    file.add_range_event("inner".to_string(), 1, 2_000, 3_000);
+    file.add_range_event("next".to_string(), 1, 11_000, 1_000);
+    // Same interval on another track doesn't interfere
+    file.add_range_event("other".to_string(), 2, 5_000, 10_000);
+    assert!(file.crossing_slices().is_empty());
+
+    file.add_range_event("crossing".to_string(), 1, 8_000, 5_000);
+    assert_eq!(file.crossing_slices(), vec![(0, 4)]);
+}
@@ -0,0 +1,20 @@
+use std::panic;
+use trace_acceptor::ParsingStateMachine;
+
+fn recorded_events() -> usize {
+    let mut transport = Vec::new();
+    tracer::flush(&mut transport);
+    let mut parser = ParsingStateMachine::default();
+    parser.parse_many(&transport).len() + parser.finish().iter().len()
+}
+
+#[test]
+fn scope_records_around_closure() {
+    let res = tracer::trace_scope("scope", || 42);
+    assert_eq!(res, 42);
+    assert_eq!(recorded_events(), 2);
+
+    let res = panic::catch_unwind(|| tracer::trace_scope("scope", || panic!("inside scope")));
+    assert!(res.is_err());
+    assert_eq!(recorded_events(), 2);
+}
@@ -0,0 +1,50 @@
+//! Internal events of the tracer, delivered to a single sink instead of the application log.
+//! By default they are forwarded to `log`.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::Path;
+use std::sync::RwLock;
+use log::warn;
+
+pub enum Diagnostic<'a> {
+    /// Id store snapshot is corrupted or from another version, store starts empty
+    IncompatibleIdSnapshot { path: &'a Path },
+    /// Sink of [MultiWriter](crate::multi_writer::MultiWriter) failed and is skipped from now on
+    SinkFailed { index: usize, error: &'a io::Error },
+    /// [FlushGuard](crate::flush_guard::FlushGuard) could not write remaining events
+    FinalFlushFailed { error: &'a io::Error },
+}
+
+impl Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Diagnostic::IncompatibleIdSnapshot { path } => {
+                write!(f, "Incompatible id store snapshot {}, starting with empty store", path.display())
+            }
+            Diagnostic::SinkFailed { index, error } => {
+                write!(f, "Trace sink #{} failed: {}. Skipping it from now on", index, error)
+            }
+            Diagnostic::FinalFlushFailed { error } => {
+                write!(f, "Failed to flush remaining events on drop: {}", error)
+            }
+        }
+    }
+}
+
+static HANDLER: RwLock<fn(&Diagnostic)> = RwLock::new(log_diagnostic);
+
+/// Default handler: thin adapter to `log`
+pub fn log_diagnostic(diagnostic: &Diagnostic) {
+    warn!("{}", diagnostic);
+}
+
+/// Replace diagnostics sink. Handler must not call into the tracer.
+pub fn set_handler(handler: fn(&Diagnostic)) {
+    *HANDLER.write().unwrap() = handler;
+}
+
+pub(crate) fn report(diagnostic: Diagnostic) {
+    let handler = *HANDLER.read().unwrap();
+    handler(&diagnostic);
+}
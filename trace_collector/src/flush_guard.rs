@@ -0,0 +1,38 @@
+//! RAII flush at scope end, so buffered events are not lost when `flush` is forgotten or skipped by early return/panic
+
+use std::io::Write;
+use crate::diagnostics;
+use crate::diagnostics::Diagnostic;
+
+/// Owns the writer and flushes current thread's events into it when dropped.
+///
+/// Must be dropped on the thread that emitted the events and before its thread-locals are destroyed:
+/// keep it in `main` or in the thread closure, not in a `static` or `thread_local!`.
+pub struct FlushGuard<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> FlushGuard<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer
+        }
+    }
+
+    /// For intermediate flushes: `tracer::flush(guard.writer())`
+    pub fn writer(&mut self) -> &mut W {
+        &mut self.writer
+    }
+}
+
+impl<W: Write> Drop for FlushGuard<W> {
+    fn drop(&mut self) {
+        let mut res = Ok(());
+        crate::thread_local_storage::with_thread_local_tracer(|tracer| {
+            res = tracer.flush_to(&mut self.writer);
+        });
+        if let Err(error) = res.and_then(|_| self.writer.flush()) {
+            diagnostics::report(Diagnostic::FinalFlushFailed { error: &error });
+        }
+    }
+}
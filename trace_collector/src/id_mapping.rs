@@ -1,8 +1,51 @@
 //! Overhead: 5*elements_cnt
 //!
 //! 256 id variants => 1280 bytes
+//! LRU bookkeeping: 8*elements_cnt => 2048 bytes
 //!
 //! get overhead ~10ns
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use crate::diagnostics;
+use crate::diagnostics::Diagnostic;
+
+/// Event id is sent in the lower 7 bits of the event start byte
+pub const MAX_ID: u8 = 0x7F;
+
+/// Where event names are interned. In both modes names are keyed by their u32 hash,
+/// so colliding names share an id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IdStoreMode {
+    /// Store per thread, no synchronization. Same name may get different ids on different threads.
+    PerThread,
+    /// One store for the whole process, behind a lock: same name gets the same id everywhere.
+    Global,
+}
+
+static GLOBAL_INTERNING: AtomicBool = AtomicBool::new(false);
+pub(crate) static GLOBAL_ID_STORE: Mutex<IdStore> = Mutex::new(IdStore::new());
+
+/// Should be set before the first event, ids interned in the other mode are not migrated
+pub fn set_id_store_mode(mode: IdStoreMode) {
+    GLOBAL_INTERNING.store(mode == IdStoreMode::Global, Ordering::Relaxed);
+}
+
+pub fn id_store_mode() -> IdStoreMode {
+    if GLOBAL_INTERNING.load(Ordering::Relaxed) {
+        IdStoreMode::Global
+    }
+    else {
+        IdStoreMode::PerThread
+    }
+}
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"TIDS";
+const SNAPSHOT_VERSION: u8 = 1;
+
 struct U32U8Map {
     keys: [Option<u32>; 256],
     values: [Option<u8>; 256],
@@ -33,6 +76,43 @@ impl U32U8Map {
         Err("Map is full")
     }
 
+    /// Backward-shift deletion: keeps probe chains intact without tombstones
+    fn remove(&mut self, key: u32) -> Option<u8> {
+        let mut hole = self.hash(key);
+        for _ in 0..256 {
+            match self.keys[hole] {
+                Some(existing_key) if existing_key == key => break,
+                Some(_) => hole = (hole + 1) % 256,
+                None => return None,
+            }
+        }
+        if self.keys[hole] != Some(key) {
+            return None;
+        }
+
+        let value = self.values[hole];
+        self.keys[hole] = None;
+        self.values[hole] = None;
+
+        let mut idx = (hole + 1) % 256;
+        for _ in 0..256 {
+            let Some(existing_key) = self.keys[idx] else {
+                break;
+            };
+            // Entry can fill the hole only if its home slot is not between the hole and itself
+            let home = self.hash(existing_key);
+            if (idx + 256 - home) % 256 >= (idx + 256 - hole) % 256 {
+                self.keys[hole] = self.keys[idx];
+                self.values[hole] = self.values[idx];
+                self.keys[idx] = None;
+                self.values[idx] = None;
+                hole = idx;
+            }
+            idx = (idx + 1) % 256;
+        }
+        value
+    }
+
     fn get(&self, key: u32) -> Option<u8> {
         let mut idx = self.hash(key);
         for _ in 0..256 {
@@ -52,27 +132,266 @@ impl U32U8Map {
 pub struct IdStore {
     id_map: U32U8Map,
     last_id: u8,
+    capacity: u8,
+
+    // Indexed by id
+    id_hashes: [u32; 256],
+    last_used: [u32; 256],
+    tick: u32,
 }
 
 impl IdStore {
+    /// Store of [MAX_ID] names, all ids the wire format can carry. Least recently used name is evicted when full
     pub const fn new() -> Self {
         Self {
             id_map: U32U8Map::new(),
             last_id: 0,
+            capacity: MAX_ID,
+
+            id_hashes: [0; 256],
+            last_used: [0; 256],
+            tick: 0,
         }
     }
 
+    /// Store holding at most `capacity` names. When full, least recently used name is evicted
+    /// and its id is reassigned to the new name.
+    pub const fn with_capacity(capacity: u8) -> Self {
+        assert!(capacity > 0 && capacity <= MAX_ID, "IdStore capacity must be in 1..=MAX_ID");
+
+        let mut store = Self::new();
+        store.capacity = capacity;
+        store
+    }
+
     /// Intended way to use: map.get_id_or_insert(id_map!("tag_name");
     pub fn insert_and_get_id(&mut self, hash: u32, tag: &str) -> u8 {
-        match self.id_map.get(hash) {
+        let id = match self.id_map.get(hash) {
             Some(v) => {
                 v
             },
             None => {
-                self.last_id += 1;
-                self.id_map.insert(hash, self.last_id).unwrap();
-                self.last_id
+                let id = if self.last_id >= self.capacity {
+                    self.evict_lru()
+                }
+                else {
+                    self.last_id += 1;
+                    self.last_id
+                };
+                self.id_map.insert(hash, id).unwrap();
+                self.id_hashes[id as usize] = hash;
+                id
+            }
+        };
+
+        self.last_used[id as usize] = self.tick;
+        self.tick = self.tick.wrapping_add(1);
+        id
+    }
+
+    /// Number of ids handed out
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> u8 {
+        self.last_id
+    }
+
+    /// Snapshot hash -> id mapping, so the next session assigns same ids to same names.
+    ///
+    /// Format: magic, version, capacity (0 means [MAX_ID]), last id, then hash (u32 LE) per id
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(7 + self.last_id as usize * 4);
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        bytes.push(SNAPSHOT_VERSION);
+        bytes.push(self.capacity);
+        bytes.push(self.last_id);
+        for id in 1..=self.last_id {
+            bytes.extend_from_slice(&self.id_hashes[id as usize].to_le_bytes());
+        }
+
+        fs::write(path, bytes)
+    }
+
+    /// Restore store written by [IdStore::save]. Missing, corrupted or incompatible snapshot gives empty store
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        match fs::read(path) {
+            Ok(bytes) => Self::from_snapshot(&bytes).unwrap_or_else(|| {
+                diagnostics::report(Diagnostic::IncompatibleIdSnapshot { path });
+                Self::new()
+            }),
+            Err(_) => Self::new(),
+        }
+    }
+
+    fn from_snapshot(bytes: &[u8]) -> Option<Self> {
+        let (header, hashes) = bytes.split_at_checked(7)?;
+        if &header[..4] != SNAPSHOT_MAGIC || header[4] != SNAPSHOT_VERSION {
+            return None;
+        }
+
+        let capacity = if header[5] == 0 { MAX_ID } else { header[5] };
+        let last_id = header[6];
+        if capacity > MAX_ID || last_id > capacity || hashes.len() != last_id as usize * 4 {
+            return None;
+        }
+
+        let mut store = Self::with_capacity(capacity);
+        for (hash, id) in hashes.chunks_exact(4).zip(1..=last_id) {
+            let hash = u32::from_le_bytes(hash.try_into().unwrap());
+            if store.id_map.get(hash).is_some() {
+                return None;
+            }
+            store.id_map.insert(hash, id).ok()?;
+            store.id_hashes[id as usize] = hash;
+        }
+        store.last_id = last_id;
+
+        Some(store)
+    }
+
+    /// Unmap least recently used name, returning its now free id
+    fn evict_lru(&mut self) -> u8 {
+        let lru_id = (1..=self.last_id)
+            .max_by_key(|&id| self.tick.wrapping_sub(self.last_used[id as usize]))
+            .unwrap();
+
+        self.id_map.remove(self.id_hashes[lru_id as usize]);
+        lru_id
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keys whose home slots are 254, 255 and 0, so probe chains collide and wrap around the table
+    fn colliding_keys(count: usize) -> Vec<u32> {
+        let map = U32U8Map::new();
+        (0..).filter(|&key| matches!(map.hash(key), 254 | 255 | 0)).take(count).collect()
+    }
+
+    #[test]
+    fn lru_name_is_evicted_first() {
+        let mut store = IdStore::with_capacity(3);
+        let a = store.insert_and_get_id(1, "a");
+        let b = store.insert_and_get_id(2, "b");
+        let c = store.insert_and_get_id(3, "c");
+        assert_eq!((a, b, c), (1, 2, 3));
+
+        // Touching `a` leaves `b` least recently used
+        assert_eq!(store.insert_and_get_id(1, "a"), a);
+        assert_eq!(store.insert_and_get_id(4, "d"), b);
+        assert_eq!(store.id_map.get(2), None);
+
+        assert_eq!(store.insert_and_get_id(2, "b"), c);
+        assert_eq!(store.id_map.get(3), None);
+        assert_eq!(store.id_map.get(1), Some(a));
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn default_store_ids_fit_the_wire_format() {
+        let mut store = IdStore::new();
+        for hash in 0..300 {
+            let id = store.insert_and_get_id(hash, "name");
+            assert!((1..=MAX_ID).contains(&id), "id {id} for name #{hash}");
+        }
+        assert_eq!(store.len(), MAX_ID);
+        assert_eq!(store.id_map.get(299), Some(store.insert_and_get_id(299, "name")));
+        assert_eq!(store.id_map.get(0), None);
+    }
+
+    #[test]
+    fn remove_keeps_colliding_keys_reachable() {
+        let keys = colliding_keys(12);
+        let mut map = U32U8Map::new();
+        for (i, &key) in keys.iter().enumerate() {
+            map.insert(key, i as u8).unwrap();
+        }
+        for &key in keys.iter().skip(1).step_by(3) {
+            assert!(map.remove(key).is_some());
+        }
+        for (i, &key) in keys.iter().enumerate() {
+            let expected = (i % 3 != 1).then_some(i as u8);
+            assert_eq!(map.get(key), expected, "key #{i}");
+        }
+        assert_eq!(map.remove(keys[1]), None);
+    }
+
+    #[test]
+    fn eviction_with_colliding_names_at_every_capacity() {
+        for capacity in 1..=MAX_ID {
+            let keys = colliding_keys(capacity as usize * 2 + 1);
+            let mut store = IdStore::with_capacity(capacity);
+            for (i, &key) in keys.iter().enumerate() {
+                store.insert_and_get_id(key, "name");
+
+                // Without re-use, the store holds exactly the newest `capacity` names
+                let kept = i.saturating_sub(capacity as usize - 1);
+                let mut ids: Vec<u8> = keys[kept..=i].iter()
+                    .map(|&key| store.id_map.get(key).unwrap_or_else(|| panic!("capacity {capacity}: lost key #{i}")))
+                    .collect();
+                for &evicted in &keys[..kept] {
+                    assert_eq!(store.id_map.get(evicted), None, "capacity {capacity}: stale key");
+                }
+                ids.sort_unstable();
+                ids.dedup();
+                assert_eq!(ids.len(), i + 1 - kept, "capacity {capacity}: shared id");
             }
         }
     }
-}
\ No newline at end of file
+
+    fn snapshot(version: u8, capacity: u8, hashes: &[u32]) -> Vec<u8> {
+        let mut bytes = SNAPSHOT_MAGIC.to_vec();
+        bytes.extend([version, capacity, hashes.len() as u8]);
+        for hash in hashes {
+            bytes.extend_from_slice(&hash.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let keys = colliding_keys(20);
+        let mut store = IdStore::with_capacity(16);
+        let ids: Vec<u8> = keys.iter().map(|&key| store.insert_and_get_id(key, "name")).collect();
+
+        let path = std::env::temp_dir().join(format!("tracer_id_snapshot_{}", std::process::id()));
+        store.save(&path).unwrap();
+        let loaded = IdStore::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.capacity, 16);
+        assert_eq!(loaded.len(), 16);
+        for (&key, &id) in keys.iter().zip(&ids).skip(4) {
+            assert_eq!(loaded.id_map.get(key), Some(id));
+        }
+        for &key in &keys[..4] {
+            assert_eq!(loaded.id_map.get(key), None);
+        }
+    }
+
+    #[test]
+    fn incompatible_snapshot_is_rejected() {
+        assert!(IdStore::from_snapshot(&snapshot(SNAPSHOT_VERSION, 0, &[1, 2, 3])).is_some());
+
+        let mut bad_magic = snapshot(SNAPSHOT_VERSION, 0, &[1]);
+        bad_magic[0] = b'X';
+        let mut truncated = snapshot(SNAPSHOT_VERSION, 0, &[1, 2]);
+        truncated.pop();
+        let mut extra = snapshot(SNAPSHOT_VERSION, 0, &[1, 2]);
+        extra.push(0);
+        let cases = [
+            ("bad magic", bad_magic),
+            ("bad version", snapshot(SNAPSHOT_VERSION + 1, 0, &[1])),
+            ("truncated", truncated),
+            ("trailing bytes", extra),
+            ("short header", SNAPSHOT_MAGIC.to_vec()),
+            ("more ids than capacity", snapshot(SNAPSHOT_VERSION, 2, &[1, 2, 3])),
+            ("capacity over MAX_ID", snapshot(SNAPSHOT_VERSION, MAX_ID + 1, &[1])),
+            ("duplicate hash", snapshot(SNAPSHOT_VERSION, 0, &[1, 2, 1])),
+        ];
+        for (case, bytes) in cases {
+            assert!(IdStore::from_snapshot(&bytes).is_none(), "{case}");
+        }
+    }
+}
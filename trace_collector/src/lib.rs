@@ -1,28 +1,155 @@
 #![feature(effects)]
 
+use std::io;
 use std::io::{BufWriter, Write};
 use std::net::UdpSocket;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::Sender;
-use interprocess::local_socket::Stream;
+use std::path::Path;
 
 pub mod thread_local_storage;
+pub mod multi_writer;
+pub mod diagnostics;
+pub mod flush_guard;
+pub mod sites;
+mod overhead;
 mod timestamp;
 mod id_mapping;
 
+pub use id_mapping::{id_store_mode, set_id_store_mode, IdStoreMode};
+pub use timestamp::{clock_mode, set_clock_mode, ClockMode};
+pub use thread_local_storage::{CleanupStrategy, ThreadLocalStorage};
+pub use sites::{set_site_enabled, sites, SiteInfo};
+pub use overhead::{measure_overhead, OverheadStats};
+
 pub fn event(hash: u32, string: &str) {
     thread_local_storage::with_thread_local_tracer(|tracer| {
         tracer.event(hash, string);
     });
 }
 
-static PACKET_NUM: AtomicUsize = AtomicUsize::new(0);
+/// Id hash of an event name, same as `tracing_event!` computes at compile time
+pub fn name_hash(name: &str) -> u32 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+/// Record `name` right before and right after running `f`, returns its result.
+/// The second event is recorded even if `f` panics.
+pub fn trace_scope<R>(name: &'static str, f: impl FnOnce() -> R) -> R {
+    struct ScopeEnd(u32, &'static str);
+
+    impl Drop for ScopeEnd {
+        fn drop(&mut self) {
+            event(self.0, self.1);
+        }
+    }
+
+    let hash = name_hash(name);
+    event(hash, name);
+    let _end = ScopeEnd(hash, name);
+    f()
+}
 
-pub fn flush(writer: &mut Stream) {
+/// Limit bytes buffered by the current thread between flushes, events over the limit are dropped.
+/// Should be called early on the thread, as buffer memory is preallocated here.
+pub fn configure_thread_buffer(capacity: usize) {
     thread_local_storage::with_thread_local_tracer(|tracer| {
-        let bytes = tracer.flush();
+        tracer.set_buffer_capacity(Some(capacity));
+    });
+}
+
+/// What to drop once the current thread's buffer limit is reached, [CleanupStrategy::DropNewest] by default
+pub fn set_cleanup_strategy(strategy: CleanupStrategy) {
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        tracer.set_cleanup_strategy(strategy);
+    });
+}
 
-        writer.write_all(&bytes).unwrap();
+pub fn cleanup_strategy() -> CleanupStrategy {
+    let mut strategy = CleanupStrategy::DropNewest;
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        strategy = tracer.cleanup_strategy();
+    });
+    strategy
+}
+
+/// Events dropped on the current thread because its buffer was full
+pub fn dropped_events() -> usize {
+    let mut dropped = 0;
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        dropped = tracer.dropped_events();
+    });
+    dropped
+}
+
+/// Cap events recorded by the current thread per second, `None` removes the cap
+pub fn set_rate_limit(per_sec: Option<u32>) {
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        tracer.set_rate_limit(per_sec);
+    });
+}
+
+/// Events dropped on the current thread by the rate limit
+pub fn rate_limited_events() -> usize {
+    let mut dropped = 0;
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        dropped = tracer.rate_limited_events();
+    });
+    dropped
+}
+
+/// Current thread's buffer is close to its limit, see [ThreadLocalStorage::is_saturated]
+pub fn is_saturated() -> bool {
+    let mut saturated = false;
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        saturated = tracer.is_saturated();
+    });
+    saturated
+}
+
+/// Limit distinct event names kept by the current thread (or the global store), least recently used are evicted when full.
+/// Capacity is clamped to 1..=127, which is also the default, as ids are 7 bits
+pub fn set_id_capacity(capacity: u8) {
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        tracer.set_id_capacity(capacity);
+    });
+}
+
+/// Save current thread's (or the global) name -> id mapping, to get the same ids in the next run
+pub fn save_id_store(path: impl AsRef<Path>) -> io::Result<()> {
+    let mut res = Ok(());
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        res = tracer.save_id_store(path);
+    });
+    res
+}
+
+/// Restore mapping written by [save_id_store]. Should be called before the first event on this thread
+pub fn load_id_store(path: impl AsRef<Path>) {
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        tracer.load_id_store(path);
+    });
+}
+
+/// Non-blocking variant of [flush]: returns `Ok(false)` if writer would block, the rest is sent on the next call
+pub fn try_flush<W: Write>(writer: &mut W) -> io::Result<bool> {
+    let mut res = Ok(true);
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        res = tracer.try_flush_to(writer);
+    });
+    res
+}
+
+static PACKET_NUM: AtomicUsize = AtomicUsize::new(0);
+
+pub fn flush<W: Write>(writer: &mut W) {
+    thread_local_storage::with_thread_local_tracer(|tracer| {
+        tracer.flush_to(writer).unwrap();
         PACKET_NUM.fetch_add(1, Ordering::Relaxed);
         // println!("Packets sent: {}", PACKET_NUM.load(Ordering::Relaxed));
     });
@@ -0,0 +1,110 @@
+//! Fan-out of flushed data into several sinks, e.g. archive file and live receiver at once
+
+use std::io;
+use std::io::Write;
+use crate::diagnostics;
+use crate::diagnostics::Diagnostic;
+
+/// Writes every buffer into all sinks. Failed sink is logged and skipped from then on,
+/// because partially written data leaves its stream desynchronized.
+pub struct MultiWriter {
+    sinks: Vec<Option<Box<dyn Write + Send>>>,
+}
+
+impl MultiWriter {
+    pub fn new(sinks: Vec<Box<dyn Write + Send>>) -> Self {
+        Self {
+            sinks: sinks.into_iter().map(Some).collect(),
+        }
+    }
+
+    pub fn alive_sinks(&self) -> usize {
+        self.sinks.iter().flatten().count()
+    }
+
+    fn for_each_sink(&mut self, mut f: impl FnMut(&mut dyn Write) -> io::Result<()>) -> io::Result<()> {
+        for (i, slot) in self.sinks.iter_mut().enumerate() {
+            if let Some(sink) = slot {
+                if let Err(error) = f(sink.as_mut()) {
+                    diagnostics::report(Diagnostic::SinkFailed { index: i, error: &error });
+                    *slot = None;
+                }
+            }
+        }
+
+        if self.alive_sinks() == 0 {
+            return Err(io::Error::other("All trace sinks failed"));
+        }
+        Ok(())
+    }
+}
+
+impl Write for MultiWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.for_each_sink(|sink| sink.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.for_each_sink(|sink| sink.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    static FAILED_SINKS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+    fn record_failure(diagnostic: &Diagnostic) {
+        if let Diagnostic::SinkFailed { index, .. } = diagnostic {
+            FAILED_SINKS.lock().unwrap().push(*index);
+        }
+    }
+
+    struct BrokenSink;
+
+    impl Write for BrokenSink {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_sink_does_not_stop_others() {
+        diagnostics::set_handler(record_failure);
+        let shared = SharedSink::default();
+        let mut writer = MultiWriter::new(vec![Box::new(BrokenSink), Box::new(shared.clone())]);
+
+        let mut expected = Vec::new();
+        for chunk in [&b"first"[..], b"second", b"third"] {
+            writer.write_all(chunk).unwrap();
+            expected.extend_from_slice(chunk);
+        }
+        writer.flush().unwrap();
+        diagnostics::set_handler(diagnostics::log_diagnostic);
+
+        assert_eq!(*shared.0.lock().unwrap(), expected);
+        assert_eq!(writer.alive_sinks(), 1);
+        // Reported once, then skipped
+        assert_eq!(*FAILED_SINKS.lock().unwrap(), [0]);
+    }
+}
@@ -0,0 +1,143 @@
+//! Runtime on/off switch for individual `tracing_event!` call sites.
+//!
+//! Each macro expansion owns a static [EventSite], checked with one relaxed load before recording.
+//! Sites register themselves on first execution, so [sites] lists only sites reached at least once,
+//! while [set_site_enabled] also applies to sites not reached yet.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+const UNREGISTERED: u8 = 0;
+const ENABLED: u8 = 1;
+const DISABLED: u8 = 2;
+
+pub struct EventSite {
+    name: &'static str,
+    state: AtomicU8,
+}
+
+impl EventSite {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            state: AtomicU8::new(UNREGISTERED),
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_enabled(&'static self) -> bool {
+        match self.state.load(Ordering::Relaxed) {
+            ENABLED => true,
+            DISABLED => false,
+            _ => self.register(),
+        }
+    }
+
+    #[cold]
+    fn register(&'static self) -> bool {
+        let mut registry = REGISTRY.lock().unwrap();
+        if self.state.load(Ordering::Relaxed) == UNREGISTERED {
+            let enabled = registry.overrides.iter()
+                .find(|(name, _)| name == self.name)
+                .is_none_or(|(_, enabled)| *enabled);
+            self.state.store(if enabled { ENABLED } else { DISABLED }, Ordering::Relaxed);
+            registry.sites.push(self);
+        }
+        self.state.load(Ordering::Relaxed) == ENABLED
+    }
+}
+
+struct Registry {
+    sites: Vec<&'static EventSite>,
+    /// State requested by name, applied to sites registering later
+    overrides: Vec<(String, bool)>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    sites: Vec::new(),
+    overrides: Vec::new(),
+});
+
+/// Turn all sites with event name `name` on or off, including the ones not reached yet
+pub fn set_site_enabled(name: &str, enabled: bool) {
+    let mut registry = REGISTRY.lock().unwrap();
+    match registry.overrides.iter_mut().find(|(n, _)| n == name) {
+        Some(entry) => entry.1 = enabled,
+        None => registry.overrides.push((name.to_string(), enabled)),
+    }
+    for site in registry.sites.iter().filter(|site| site.name == name) {
+        site.state.store(if enabled { ENABLED } else { DISABLED }, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteInfo {
+    pub name: &'static str,
+    pub enabled: bool,
+}
+
+/// Sites reached at least once, in order of first execution. Same name is listed once per call site.
+pub fn sites() -> Vec<SiteInfo> {
+    REGISTRY.lock().unwrap().sites.iter()
+        .map(|site| SiteInfo {
+            name: site.name,
+            enabled: site.state.load(Ordering::Relaxed) == ENABLED,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Registry is process-wide, each test uses its own names
+
+    fn listed(name: &str) -> Vec<SiteInfo> {
+        sites().into_iter().filter(|site| site.name == name).collect()
+    }
+
+    #[test]
+    fn disabled_before_first_hit() {
+        static SITE: EventSite = EventSite::new("sites::disabled_before_first_hit");
+        set_site_enabled(SITE.name, false);
+        assert!(listed(SITE.name).is_empty());
+
+        assert!(!SITE.is_enabled());
+        assert_eq!(listed(SITE.name), [SiteInfo { name: SITE.name, enabled: false }]);
+    }
+
+    #[test]
+    fn toggles_registered_sites() {
+        static FIRST: EventSite = EventSite::new("sites::toggles_registered_sites");
+        static SECOND: EventSite = EventSite::new("sites::toggles_registered_sites");
+        assert!(FIRST.is_enabled());
+        assert!(SECOND.is_enabled());
+
+        set_site_enabled(FIRST.name, false);
+        assert!(!FIRST.is_enabled());
+        assert!(!SECOND.is_enabled());
+
+        set_site_enabled(FIRST.name, true);
+        assert!(FIRST.is_enabled());
+        assert!(SECOND.is_enabled());
+    }
+
+    #[test]
+    fn lists_reached_sites_once_per_call_site() {
+        static A: EventSite = EventSite::new("sites::lists_a");
+        static B: EventSite = EventSite::new("sites::lists_b");
+        static B_AGAIN: EventSite = EventSite::new("sites::lists_b");
+        static UNREACHED: EventSite = EventSite::new("sites::lists_unreached");
+
+        assert!(B.is_enabled());
+        assert!(A.is_enabled());
+        assert!(A.is_enabled());
+        assert!(B_AGAIN.is_enabled());
+
+        let ours: Vec<_> = sites().into_iter().filter(|site| site.name.starts_with("sites::lists_")).collect();
+        let names: Vec<_> = ours.iter().map(|site| site.name).collect();
+        assert_eq!(names, ["sites::lists_b", "sites::lists_a", "sites::lists_b"]);
+        assert!(ours.iter().all(|site| site.enabled));
+        assert!(listed(UNREACHED.name).is_empty());
+    }
+}
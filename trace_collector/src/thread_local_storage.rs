@@ -1,59 +1,478 @@
 use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use log::info;
-use crate::id_mapping::IdStore;
+use crate::id_mapping::{id_store_mode, IdStore, IdStoreMode, GLOBAL_ID_STORE, MAX_ID};
 use crate::timestamp::capture_timestamp;
 
+/// What to drop when thread buffer is full
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CleanupStrategy {
+    /// Reject incoming events, keeping the beginning of the capture
+    DropNewest,
+    /// Discard oldest buffered events, a quarter of capacity at a time (flight recorder)
+    DropOldest,
+}
+
+/// Token bucket allowing `per_sec` events per second, with bursts up to one second worth
+struct RateLimit {
+    per_sec: u32,
+    tokens: u32,
+    refilled: Instant,
+}
+
+impl RateLimit {
+    fn new(per_sec: u32) -> Self {
+        Self {
+            per_sec,
+            tokens: per_sec,
+            refilled: Instant::now(),
+        }
+    }
+
+    #[inline(always)]
+    fn take(&mut self) -> bool {
+        // Clock is only read once the bucket is empty
+        if self.tokens == 0 {
+            self.refill();
+        }
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    #[cold]
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let new_tokens = (now - self.refilled).as_nanos() * self.per_sec as u128 / 1_000_000_000;
+        if new_tokens >= self.per_sec as u128 {
+            self.tokens = self.per_sec;
+            self.refilled = now;
+        }
+        else if new_tokens > 0 {
+            self.tokens = new_tokens as u32;
+            // Keep the fraction of a token already accumulated
+            self.refilled += Duration::from_nanos((new_tokens * 1_000_000_000 / self.per_sec as u128) as u64);
+        }
+    }
+}
+
+/// Event buffer with its own id store and limits. Backs the per-thread default tracer, and can be created
+/// directly as an independent instance, e.g. to capture a subsystem into a separate file:
+/// `tracing_event!(storage, "name")` + [ThreadLocalStorage::flush_to].
+///
+/// Only the default tracer follows [IdStoreMode]. An independent instance always interns into its own store,
+/// so in [IdStoreMode::Global] it can't evict names of the default tracer.
 pub struct ThreadLocalStorage {
     buf: Vec<u8>,
-    id_store: IdStore
+    id_store: IdStore,
+    /// Always use `id_store`, even in [IdStoreMode::Global]
+    own_id_store: bool,
+
+    capacity: Option<usize>,
+    cleanup_strategy: CleanupStrategy,
+    dropped_events: usize,
+    rate_limit: Option<RateLimit>,
+    rate_limited_events: usize,
+    /// TSC period of the last captured event, deltas of this buffer's stream count from it
+    prev_pr: u64,
+    /// Period delta of dropped events, carried into the next written event
+    pending_pr: u64,
+    /// Leading bytes of `buf` which are the rest of a partially written event, must be sent as is
+    partial_head: usize,
+}
+
+impl fmt::Debug for ThreadLocalStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadLocalStorage")
+            .field("buffered_bytes", &self.buf.len())
+            .field("capacity", &self.capacity)
+            .field("cleanup_strategy", &self.cleanup_strategy)
+            .field("dropped_events", &self.dropped_events)
+            .field("rate_limited_events", &self.rate_limited_events)
+            .finish_non_exhaustive()
+    }
 }
 
 impl ThreadLocalStorage {
     pub const fn new()-> Self {
         ThreadLocalStorage {
             buf: Vec::new(),
-            id_store: IdStore::new()
+            id_store: IdStore::new(),
+            own_id_store: true,
+
+            capacity: None,
+            cleanup_strategy: CleanupStrategy::DropNewest,
+            dropped_events: 0,
+            rate_limit: None,
+            rate_limited_events: 0,
+            prev_pr: 0,
+            pending_pr: 0,
+            partial_head: 0,
+        }
+    }
+
+    /// Limit buffered bytes of this thread and preallocate them. Events not fitting until next flush are dropped.
+    /// `None` removes the limit.
+    pub fn set_buffer_capacity(&mut self, capacity: Option<usize>) {
+        if let Some(capacity) = capacity {
+            self.buf.reserve(capacity.saturating_sub(self.buf.len()));
         }
+        self.capacity = capacity;
+    }
+
+    /// Buffer limit set by [ThreadLocalStorage::set_buffer_capacity], `None` if unbounded
+    pub fn buffer_capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    /// Bytes waiting for the next flush
+    pub fn buffered_bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Buffer is at least 3/4 full, events will soon be dropped unless flushed.
+    /// Instrumentation can check it to voluntarily reduce its rate. Always `false` if unbounded.
+    #[inline(always)]
+    pub fn is_saturated(&self) -> bool {
+        self.capacity.is_some_and(|capacity| self.buf.len() >= capacity - capacity / 4)
+    }
+
+    pub fn set_cleanup_strategy(&mut self, strategy: CleanupStrategy) {
+        self.cleanup_strategy = strategy;
+    }
+
+    pub fn cleanup_strategy(&self) -> CleanupStrategy {
+        self.cleanup_strategy
+    }
+
+    /// Number of events dropped because the buffer was full
+    pub fn dropped_events(&self) -> usize {
+        self.dropped_events
+    }
+
+
+    /// Record at most `per_sec` events per second, the rest is dropped and counted in
+    /// [ThreadLocalStorage::rate_limited_events]. `None` removes the limit.
+    pub fn set_rate_limit(&mut self, per_sec: Option<u32>) {
+        self.rate_limit = per_sec.map(RateLimit::new);
+    }
+
+    /// Number of events dropped by the rate limit, not included in [ThreadLocalStorage::dropped_events]
+    pub fn rate_limited_events(&self) -> usize {
+        self.rate_limited_events
+    }
+
+    /// Storage of the default tracer, interning into the store of current [IdStoreMode]
+    const fn thread_default() -> Self {
+        let mut storage = Self::new();
+        storage.own_id_store = false;
+        storage
+    }
+
+    /// Run `f` on the store of current [IdStoreMode]
+    fn with_id_store<R>(&mut self, f: impl FnOnce(&mut IdStore) -> R) -> R {
+        if self.own_id_store {
+            return f(&mut self.id_store);
+        }
+        match id_store_mode() {
+            IdStoreMode::PerThread => f(&mut self.id_store),
+            IdStoreMode::Global => f(&mut GLOBAL_ID_STORE.lock().unwrap()),
+        }
+    }
+
+    /// Bound the number of interned names, least recently used are evicted when full.
+    /// Names already interned in the store are forgotten. Clamped to `1..=MAX_ID` (127).
+    pub fn set_id_capacity(&mut self, capacity: u8) {
+        // Built before taking the global store lock
+        let new_store = IdStore::with_capacity(capacity.clamp(1, MAX_ID));
+        self.with_id_store(|store| *store = new_store);
+    }
+
+    pub fn save_id_store(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.with_id_store(|store| store.save(path))
     }
 
+    pub fn load_id_store(&mut self, path: impl AsRef<Path>) {
+        let new_store = IdStore::load(path);
+        self.with_id_store(|store| *store = new_store);
+    }
 
     pub fn event(&mut self, hash: u32, string: &str) {
-        let (mut dif_pr, now) = capture_timestamp();
-        let mut buf = [0; 11];
-        let v = self.id_store.insert_and_get_id(hash, string);
+        let (dif_pr, now) = capture_timestamp(&mut self.prev_pr);
+        let total_pr = dif_pr + self.pending_pr;
+        if let Some(rate_limit) = &mut self.rate_limit {
+            if !rate_limit.take() {
+                self.pending_pr = total_pr;
+                self.rate_limited_events += 1;
+                return;
+            }
+        }
+        let v = self.with_id_store(|store| store.insert_and_get_id(hash, string));
+        self.push_record(v, now, total_pr);
+    }
 
-        buf[0] = v | 0x80;
+    /// Append encoded event, `total_pr` already includes `pending_pr`
+    fn push_record(&mut self, id: u8, now: u16, mut total_pr: u64) {
+        let mut buf = [0; 13];
+        buf[0] = id | 0x80;
         buf[1] = (now >> 8) as u8;
         buf[2] = now as u8;
 
-        let mut ind = 3;
-        // While value is 64-16 = 48 bits, we send 7 bits at a time
-        while dif_pr > 0 {
-            buf[ind] = dif_pr as u8 & 0x7F;
+        let mut ind = 3 + encode_pr(total_pr, &mut buf[3..]);
 
-            dif_pr >>= 7;
-            ind += 1;
+        if let Some(capacity) = self.capacity {
+            if self.buf.len() + ind > capacity {
+                if self.cleanup_strategy == CleanupStrategy::DropOldest {
+                    let unfolded_pr = self.drop_oldest((capacity / 4).max(ind));
+                    if unfolded_pr > 0 {
+                        total_pr += unfolded_pr;
+                        ind = 3 + encode_pr(total_pr, &mut buf[3..]);
+                    }
+                }
+                if self.buf.len() + ind > capacity {
+                    // Keep period delta, otherwise all following timestamps of this thread would shift
+                    self.pending_pr = total_pr;
+                    self.dropped_events += 1;
+                    return;
+                }
+            }
         }
+        self.pending_pr = 0;
 
         // Write event packet
         self.buf.extend_from_slice(&buf[..ind])
     }
 
+    /// Remove whole events from the front until at least `bytes` are freed. Their period deltas are folded
+    /// into the first kept event, so following timestamps stay correct. If no event is kept, the dropped
+    /// deltas are returned and must go into the next written event.
+    fn drop_oldest(&mut self, bytes: usize) -> u64 {
+        let start = self.partial_head;
+        let mut end = start;
+        let mut dropped_pr = 0;
+        while end < self.buf.len() && end - start < bytes {
+            let (len, pr) = decode_record(&self.buf[end..]);
+            end += len;
+            dropped_pr += pr;
+            self.dropped_events += 1;
+        }
+
+        if end == self.buf.len() {
+            self.buf.truncate(start);
+            return dropped_pr;
+        }
+
+        let (len, pr) = decode_record(&self.buf[end..]);
+        let mut head = [0; 13];
+        head[..3].copy_from_slice(&self.buf[end..end + 3]);
+        let head_len = 3 + encode_pr(pr + dropped_pr, &mut head[3..]);
+        self.buf.splice(start..end + len, head[..head_len].iter().copied());
+        0
+    }
+
+    /// Write buffered events without intermediate copy. On error the unsent events stay buffered for the next attempt.
+    pub fn flush_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        if self.try_flush_to(writer)? {
+            Ok(())
+        }
+        else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    /// Write as much as the writer accepts. Written bytes are removed right away, so a short write, `WouldBlock`
+    /// or error leaves exactly the unsent remainder, and the next call resumes at the same stream position.
+    ///
+    /// Returns `false` if writer would block before the whole buffer was sent.
+    pub fn try_flush_to<W: Write>(&mut self, writer: &mut W) -> io::Result<bool> {
+        let mut written = 0;
+        let res = loop {
+            if written == self.buf.len() {
+                break Ok(true);
+            }
+            match writer.write(&self.buf[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+                Err(e) => break Err(e),
+            }
+        };
+
+        if written == self.buf.len() {
+            self.partial_head = 0;
+        }
+        else {
+            // Find where the event cut by this write ends
+            let mut pos = self.partial_head;
+            while pos < written {
+                pos += decode_record(&self.buf[pos..]).0;
+            }
+            self.partial_head = pos - written;
+        }
+        self.buf.drain(..written);
+        res
+    }
+
+    /// Drop all buffered events
+    pub(crate) fn discard(&mut self) {
+        self.buf.clear();
+        self.partial_head = 0;
+        self.pending_pr = 0;
+    }
+
     pub fn flush(&mut self) -> Box<[u8]> {
         let clone = self.buf.clone().into_boxed_slice();
         self.buf.clear();
+        self.partial_head = 0;
         clone
     }
 }
 
+/// Write period delta 7 bits per byte, returns number of bytes
+#[inline(always)]
+fn encode_pr(mut pr: u64, out: &mut [u8]) -> usize {
+    let mut ind = 0;
+    // While value is 64-16 = 48 bits (more if carried from dropped events), we send 7 bits at a time
+    while pr > 0 {
+        out[ind] = pr as u8 & 0x7F;
+
+        pr >>= 7;
+        ind += 1;
+    }
+    ind
+}
+
+/// Length and period delta of the event record at the start of `buf`
+fn decode_record(buf: &[u8]) -> (usize, u64) {
+    let mut len = 3;
+    let mut pr = 0;
+    while len < buf.len() && buf[len] & 0x80 == 0 {
+        pr |= (buf[len] as u64) << (7 * (len - 3));
+        len += 1;
+    }
+    (len, pr)
+}
+
 
 pub fn with_thread_local_tracer<F>(f: F)
 where F: FnOnce(&mut ThreadLocalStorage) {
     thread_local! {
-        static TRACER: RefCell<ThreadLocalStorage> = RefCell::new(ThreadLocalStorage::new());
+        static TRACER: RefCell<ThreadLocalStorage> = RefCell::new(ThreadLocalStorage::thread_default());
     }
 
     TRACER.with_borrow_mut(|tracer| {
         f(tracer)
     });
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Id store mode is process-wide, tests switching it must not overlap
+    static GLOBAL_MODE: Mutex<()> = Mutex::new(());
+
+    fn buffered_pr(storage: &ThreadLocalStorage) -> (usize, u64) {
+        let mut pos = 0;
+        let mut records = 0;
+        let mut pr = 0;
+        while pos < storage.buf.len() {
+            let (len, record_pr) = decode_record(&storage.buf[pos..]);
+            pos += len;
+            records += 1;
+            pr += record_pr;
+        }
+        (records, pr)
+    }
+
+    #[test]
+    fn out_of_range_id_capacity_is_clamped() {
+        let _mode = GLOBAL_MODE.lock().unwrap();
+        crate::set_id_store_mode(IdStoreMode::Global);
+        let mut storage = ThreadLocalStorage::thread_default();
+        storage.set_id_capacity(0);
+        storage.set_id_capacity(200);
+        // Global store lock is not poisoned
+        storage.event(0x1234, "after clamp");
+        crate::set_id_store_mode(IdStoreMode::PerThread);
+
+        storage.set_id_capacity(u8::MAX);
+        storage.event(0x1234, "after clamp");
+    }
+
+    #[test]
+    fn overhead_probe_keeps_global_store_intact() {
+        let _mode = GLOBAL_MODE.lock().unwrap();
+        crate::set_id_store_mode(IdStoreMode::Global);
+        let before = GLOBAL_ID_STORE.lock().unwrap().len();
+        crate::measure_overhead(100);
+        let after = GLOBAL_ID_STORE.lock().unwrap().len();
+        crate::set_id_store_mode(IdStoreMode::PerThread);
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn explicit_instance_keeps_global_store_intact() {
+        let _mode = GLOBAL_MODE.lock().unwrap();
+        crate::set_id_store_mode(IdStoreMode::Global);
+        let before = GLOBAL_ID_STORE.lock().unwrap().len();
+        let mut storage = ThreadLocalStorage::new();
+        for hash in 0..200 {
+            storage.event(hash, "subsystem");
+        }
+        let after = GLOBAL_ID_STORE.lock().unwrap().len();
+        crate::set_id_store_mode(IdStoreMode::PerThread);
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn drop_all_oldest_carries_pr_into_new_event() {
+        let mut storage = ThreadLocalStorage::new();
+        storage.set_buffer_capacity(Some(6));
+        storage.set_cleanup_strategy(CleanupStrategy::DropOldest);
+
+        storage.push_record(1, 0, 5);
+        storage.push_record(1, 0, 1);
+        assert_eq!(buffered_pr(&storage), (1, 6));
+        assert_eq!(storage.dropped_events(), 1);
+    }
+
+    #[test]
+    fn small_capacity_keeps_total_pr() {
+        for strategy in [CleanupStrategy::DropNewest, CleanupStrategy::DropOldest] {
+            for capacity in 3..40 {
+                let mut storage = ThreadLocalStorage::new();
+                storage.set_buffer_capacity(Some(capacity));
+                storage.set_cleanup_strategy(strategy);
+
+                let mut seed = 12345u64;
+                let mut total = 0;
+                for i in 0..500 {
+                    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    // Mostly zero and small deltas, sometimes multi-byte ones
+                    let pr = match seed >> 60 {
+                        0..8 => 0,
+                        8..14 => (seed >> 40) & 0x7F,
+                        _ => (seed >> 20) & 0xF_FFFF,
+                    };
+                    total += pr;
+                    storage.push_record(1, 0, pr + storage.pending_pr);
+
+                    let (records, pr) = buffered_pr(&storage);
+                    assert_eq!(pr + storage.pending_pr, total, "{strategy:?}, capacity {capacity}, event #{i}");
+                    assert_eq!(records + storage.dropped_events(), i + 1);
+                    assert!(storage.buf.len() <= capacity);
+                }
+            }
+        }
+    }
+}
@@ -1,40 +1,59 @@
-use std::arch::x86_64::{__rdtscp, _mm_lfence};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::arch::x86_64::{__rdtscp, _mm_lfence, _rdtsc};
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::time::UNIX_EPOCH;
 
+/// How strictly TSC read is ordered with the measured code
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockMode {
+    /// `rdtsc`: cheapest, may be reordered with surrounding instructions
+    Fast,
+    /// `rdtscp`: waits for previous instructions to complete, later ones may start earlier
+    Ordered,
+    /// `lfence; rdtscp; lfence`: fully ordered, for nanosecond-scale regions. Costs most
+    Precise,
+}
+
+static CLOCK_MODE: AtomicU8 = AtomicU8::new(ClockMode::Ordered as u8);
+
+pub fn set_clock_mode(mode: ClockMode) {
+    CLOCK_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn clock_mode() -> ClockMode {
+    match CLOCK_MODE.load(Ordering::Relaxed) {
+        0 => ClockMode::Fast,
+        1 => ClockMode::Ordered,
+        _ => ClockMode::Precise,
+    }
+}
+
 #[inline(always)]
 pub fn now() -> u64 {
     unsafe {
         let mut aux: u32 = 0;
-        // _mm_lfence();
-        let v = __rdtscp(&mut aux as *mut u32);
-        // _mm_lfence();
+        match clock_mode() {
+            ClockMode::Fast => _rdtsc(),
+            ClockMode::Ordered => __rdtscp(&mut aux as *mut u32),
+            ClockMode::Precise => {
+                _mm_lfence();
+                let v = __rdtscp(&mut aux as *mut u32);
+                _mm_lfence();
 
-        v
+                v
+            }
+        }
     }
     // UNIX_EPOCH.elapsed().unwrap().as_nanos() as u64
 }
 
-static PREV_PERIOD: AtomicU64 = AtomicU64::new(0);
-
-pub fn capture_timestamp() -> (u64, u16) {
-    let mut prev_pr = PREV_PERIOD.load(Ordering::Relaxed);
-    unsafe {
-        loop {
-            let now = now();
-            // let now = 8234721;
-            let now_pr = (now >> 16) as u64;
-            let dif_pr = now_pr.wrapping_sub(prev_pr) & 0xFFFF_FFFF_FFFF;
-            if dif_pr > 0 {
-                match PREV_PERIOD.compare_exchange(prev_pr, now_pr, Ordering::Relaxed, Ordering::Relaxed) {
-                    Ok(_) => {
-                        return (dif_pr, now as u16);
-                    },
-                    Err(x) => prev_pr = x
-                }
-            } else {
-                return (0, now as u16);
-            }
-        }
-    }
-}
\ No newline at end of file
+/// Read TSC as lower 16 bits and the number of 2^16 cycle periods passed since `prev_pr`, which is advanced.
+/// Baseline belongs to one event stream: with a shared one, each stream would skip periods captured by the others
+#[inline(always)]
+pub fn capture_timestamp(prev_pr: &mut u64) -> (u64, u16) {
+    let now = now();
+    let now_pr = now >> 16;
+    let dif_pr = now_pr.wrapping_sub(*prev_pr) & 0xFFFF_FFFF_FFFF;
+    *prev_pr = now_pr;
+    (dif_pr, now as u16)
+}
@@ -0,0 +1,64 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use tracer::ThreadLocalStorage;
+use tracer_macro::tracing_event;
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn event_does_not_allocate() {
+    let mut storage = ThreadLocalStorage::new();
+    storage.set_buffer_capacity(Some(64 * 1024));
+    // Thread-local default tracer is created on first use
+    tracer::configure_thread_buffer(64 * 1024);
+
+    let before = ALLOCATIONS.with(|a| a.get());
+    for i in 0..1000u32 {
+        storage.event(i % 100, "event");
+        tracer::event(i % 100, "event");
+    }
+    assert_eq!(ALLOCATIONS.with(|a| a.get()), before);
+}
+
+fn macro_sites(storage: &mut ThreadLocalStorage) {
+    tracing_event!("macro site");
+    tracing_event!(storage, "explicit macro site");
+}
+
+#[test]
+fn macro_site_does_not_allocate_after_first_hit() {
+    let mut storage = ThreadLocalStorage::new();
+    storage.set_buffer_capacity(Some(64 * 1024));
+    tracer::configure_thread_buffer(64 * 1024);
+    // Sites register on first execution, which may allocate
+    macro_sites(&mut storage);
+
+    let before = ALLOCATIONS.with(|a| a.get());
+    for _ in 0..1000 {
+        macro_sites(&mut storage);
+    }
+    assert_eq!(ALLOCATIONS.with(|a| a.get()), before);
+}
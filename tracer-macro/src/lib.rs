@@ -1,21 +1,61 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{LitStr, parse_macro_input};
+use syn::{Expr, LitStr, parse_macro_input, Token};
+use syn::parse::{Parse, ParseStream};
 
 
+/// `tracing_event!("name")` records into the calling thread's default tracer,
+/// `tracing_event!(storage, "name")` into an explicit `ThreadLocalStorage` instance.
+/// Every call site can be switched off at runtime with `tracer::set_site_enabled`.
 #[proc_macro]
 pub fn tracing_event(input: TokenStream) -> TokenStream {
-    let input = parse_macro_input!(input as LitStr);
-    let s = input.value();
+    let input = parse_macro_input!(input as EventInput);
+    let s = input.name.value();
     let id = get_hash(&s) as u32;
 
+    let record = match input.target {
+        Some(target) => quote! {
+            (#target).event(#id, #s)
+        },
+        None => quote! {
+            tracer::event(#id, #s)
+        },
+    };
     let expanded = quote! {
-        tracer::event(#id, #s)
+        {
+            static SITE: tracer::sites::EventSite = tracer::sites::EventSite::new(#s);
+            if SITE.is_enabled() {
+                #record
+            }
+        }
     };
 
     TokenStream::from(expanded)
 }
 
+struct EventInput {
+    target: Option<Expr>,
+    name: LitStr,
+}
+
+impl Parse for EventInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.peek(LitStr) {
+            return Ok(Self {
+                target: None,
+                name: input.parse()?,
+            });
+        }
+
+        let target = input.parse()?;
+        input.parse::<Token![,]>()?;
+        Ok(Self {
+            target: Some(target),
+            name: input.parse()?,
+        })
+    }
+}
+
 fn get_hash(s: &str) -> u64 {
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
@@ -23,4 +63,4 @@ fn get_hash(s: &str) -> u64 {
     let mut hasher = DefaultHasher::new();
     s.hash(&mut hasher);
     hasher.finish()
-}
\ No newline at end of file
+}